@@ -0,0 +1,25 @@
+#![no_main]
+
+// v1 index lookups only, no filesystem. The input is the body of an archive with FLAG_INDEX, the
+// header is made up so the fuzzer doesn't have to find it
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use archive_testing::{Format,Header,extract_v1};
+use archive_testing::header::FLAG_INDEX;
+use archive_testing::index::Index;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut header = Header::new(Format::V1);
+    header.flags |= FLAG_INDEX;
+    let mut buf = header.to_bytes().to_vec();
+    buf.extend_from_slice(data);
+    let Ok(Some(index)) = Index::parse(&buf, &header) else { return };
+    for entry in index.entries() {
+        let Ok(entry) = entry else { return };
+        let _ = index.find(entry.path);
+        let _ = extract_v1(&buf, Path::new(OsStr::from_bytes(entry.path)), false);
+    }
+});
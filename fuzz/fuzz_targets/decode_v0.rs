@@ -0,0 +1,21 @@
+#![no_main]
+
+// v0 header validation only, no filesystem: anything that parses must be safe to walk
+use archive_testing::v0::Archive;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for archive in [Archive::parse(data), Archive::parse_legacy(data)] {
+        let Ok(archive) = archive else { continue };
+        let stats = archive.stats();
+        assert_eq!(archive.dirs().count(), stats.dirs);
+        let mut files = 0;
+        let mut data_bytes = 0;
+        for (_name, contents) in archive.files() {
+            files += 1;
+            data_bytes += contents.len() as u64;
+        }
+        assert_eq!(files, stats.files);
+        assert_eq!(data_bytes, stats.data_bytes);
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+// v1 decoding only, no filesystem
+use archive_testing::Stats;
+use archive_testing::v1::Decoder;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut stats = Stats::default();
+    for message in Decoder::new(data) {
+        match message {
+            Ok(message) => message.count(&mut stats),
+            Err(_) => return,
+        }
+    }
+    assert!(stats.data_bytes + stats.name_bytes <= data.len() as u64);
+});
@@ -0,0 +1,61 @@
+#![no_main]
+
+// The streaming decoder has to agree with the slice decoder on every input: same messages, and
+// an error wherever the slice decoder errors
+use archive_testing::{Error,Format,Header};
+use archive_testing::header::verify_trailer;
+use archive_testing::v1::{Decoder,Message};
+use archive_testing::v1stream::{StreamDecoder,StreamMessage};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = Decoder::new(data);
+    // tiny buffer so names and data straddle refills
+    let mut stream = StreamDecoder::new(std::io::BufReader::with_capacity(7, data));
+    // with a v1 header both take their flags (checksums, compression, ...) from it. The slice
+    // decoder leaves the trailer to verify_trailer, so only archives that pass it are compared
+    if let Ok(header) = Header::parse(data) {
+        if header.format != Format::V1 || verify_trailer(data, &header).is_err() { return; }
+        decoder = decoder.with_header(&header);
+        stream.read_header().unwrap();
+    }
+    loop {
+        match (decoder.next_message(), stream.next_message()) {
+            (Ok(None), Ok(None)) => break,
+            (Err(_), Err(_)) => break,
+            (Ok(Some(Message::File { name, data })), Ok(Some(StreamMessage::File { name: sname, len }))) => {
+                assert_eq!(name, sname.as_c_str());
+                assert_eq!(data.len() as u64, len);
+                let mut out = vec![];
+                stream.copy_data(&mut out).unwrap();
+                assert_eq!(data, &out[..]);
+            },
+            (Ok(Some(Message::Compressed { name, file })), Ok(Some(StreamMessage::File { name: sname, len }))) => {
+                assert_eq!(name, sname.as_c_str());
+                assert_eq!(file.size, len);
+                let mut out = vec![];
+                match (file.to_vec(), stream.copy_data(&mut out)) {
+                    (Ok(data), Ok(())) => assert_eq!(data, out),
+                    (Err(_), Err(_)) => break,
+                    (a, b) => panic!("decompression disagrees: {a:?} vs {b:?}"),
+                }
+            },
+            (Ok(Some(Message::Dir { name, empty })), Ok(Some(StreamMessage::Dir { name: sname, empty: sempty }))) => {
+                assert_eq!(name, sname.as_c_str());
+                assert_eq!(empty, sempty);
+            },
+            (Ok(Some(Message::Pop)), Ok(Some(StreamMessage::Pop))) => {},
+            // the stream decoder only finds out the data is short when it copies it
+            (Err(Error::Truncated { .. }), Ok(Some(StreamMessage::File { .. }))) => {
+                assert!(stream.copy_data(&mut std::io::sink()).is_err());
+                break;
+            },
+            // same for a file checksum, which it can only check after the data
+            (Err(Error::Checksum { .. }), Ok(Some(StreamMessage::File { .. }))) => {
+                assert!(matches!(stream.copy_data(&mut std::io::sink()), Err(Error::Checksum { .. })));
+                break;
+            },
+            (a, b) => panic!("decoders disagree: {a:?} vs {b:?}"),
+        }
+    }
+});
@@ -0,0 +1,60 @@
+// Shared by the unpack_* targets: a scratch destination to unpack into and a check that nothing
+// escaped it. Everything unpacks with Confinement::Openat2 so the fuzzer process itself isn't
+// chrooted after the first input, and with limits so an input can't fill the disk.
+
+use std::fs::{self,File};
+use std::io::{Seek,Write};
+use std::os::fd::FromRawFd;
+use std::path::PathBuf;
+
+use archive_testing::{Confinement,UnpackLimits,UnpackOptions};
+
+pub struct Scratch {
+    // only ever contains dest, anything else in here escaped
+    base: PathBuf,
+    pub dest: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(target: &str) -> Scratch {
+        let base = std::env::temp_dir().join(format!("archive-testing-fuzz-{target}-{}", std::process::id()));
+        let dest = base.join("dest");
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&dest).unwrap();
+        Scratch { base, dest }
+    }
+
+    /// Empties dest for the next unpack
+    pub fn reset(&self) {
+        fs::remove_dir_all(&self.dest).unwrap();
+        fs::create_dir(&self.dest).unwrap();
+    }
+
+    /// Panics (so libFuzzer saves the input) if the unpack created anything outside dest
+    pub fn check_contained(&self) {
+        for entry in fs::read_dir(&self.base).unwrap() {
+            let entry = entry.unwrap();
+            assert!(entry.path() == self.dest, "unpack escaped the destination: {:?}", entry.path());
+        }
+    }
+}
+
+pub fn options(copy_file_range: bool) -> UnpackOptions {
+    let limits = UnpackLimits {
+        max_total_bytes: 1 << 24,
+        max_files: 4096,
+        max_dirs: 4096,
+        ..UnpackLimits::default()
+    };
+    UnpackOptions { copy_file_range, confinement: Confinement::Openat2, limits, ..UnpackOptions::default() }
+}
+
+/// The unpackers take a File, so put the input in a memfd to keep the disk out of it
+pub fn memfd(data: &[u8]) -> File {
+    let fd = unsafe { libc::memfd_create(c"fuzz-input".as_ptr(), libc::MFD_CLOEXEC) };
+    assert!(fd >= 0, "memfd_create failed");
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data).unwrap();
+    file.rewind().unwrap();
+    file
+}
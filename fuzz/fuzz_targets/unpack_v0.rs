@@ -0,0 +1,22 @@
+#![no_main]
+
+// Both the write and copy_file_range paths, into a scratch dir
+use std::sync::OnceLock;
+
+use archive_testing::unpack_v0;
+use libfuzzer_sys::fuzz_target;
+
+mod scratch;
+use scratch::{Scratch,memfd,options};
+
+static SCRATCH: OnceLock<Scratch> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let scratch = SCRATCH.get_or_init(|| Scratch::new("unpack_v0"));
+    let infile = memfd(data);
+    for copy_file_range in [false, true] {
+        scratch.reset();
+        let _ = unpack_v0(&infile, &scratch.dest, &options(copy_file_range));
+        scratch.check_contained();
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use archive_testing::{UnpackOptions,unpack_v1_parallel};
+use libfuzzer_sys::fuzz_target;
+
+mod scratch;
+use scratch::{Scratch,memfd,options};
+
+static SCRATCH: OnceLock<Scratch> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let scratch = SCRATCH.get_or_init(|| Scratch::new("unpack_v1_parallel"));
+    scratch.reset();
+    // more than one thread but not one per cpu for every input
+    let opts = UnpackOptions { threads: 2, ..options(false) };
+    let _ = unpack_v1_parallel(&memfd(data), &scratch.dest, &opts);
+    scratch.check_contained();
+});
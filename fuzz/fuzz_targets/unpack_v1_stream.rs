@@ -0,0 +1,18 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use archive_testing::unpack_v1_stream;
+use libfuzzer_sys::fuzz_target;
+
+mod scratch;
+use scratch::{Scratch,memfd,options};
+
+static SCRATCH: OnceLock<Scratch> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let scratch = SCRATCH.get_or_init(|| Scratch::new("unpack_v1_stream"));
+    scratch.reset();
+    let _ = unpack_v1_stream(memfd(data), &scratch.dest, &options(false));
+    scratch.check_contained();
+});
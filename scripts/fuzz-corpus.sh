@@ -0,0 +1,50 @@
+#!/bin/bash
+
+# Seeds the cargo-fuzz corpora with archives from pack_v0, pack_v1 and make_malicious, then
+# fuzzing is eg. `cargo +nightly fuzz run unpack_v1` from the repo root. Seeds are kept small
+# since libFuzzer's default -max_len is 4096
+
+set -e
+
+cargo build --release &> /dev/null
+
+bin=$(realpath target/release/archive-testing)
+corpus=$(realpath fuzz)/corpus
+tmp=$(mktemp -d)
+trap 'rm -rf $tmp' EXIT
+
+# a small tree with some nesting, an empty file, an empty dir and a non ascii name
+mkdir -p $tmp/tree/a/b/c $tmp/tree/empty $tmp/tree/$'caf\xc3\xa9'
+echo hello > $tmp/tree/a/hello
+echo world > $tmp/tree/a/b/c/world
+touch $tmp/tree/a/b/nothing
+printf 'x%.0s' {1..300} > $tmp/tree/$'caf\xc3\xa9'/xs
+mkdir $tmp/emptytree
+
+for target in decode_v0 unpack_v0 unpack_v0_ring unpack_v0_parallel decode_v1 decode_v1_stream decode_index unpack_v1 unpack_v1_stream unpack_v1_ring unpack_v1_parallel; do
+    mkdir -p $corpus/$target
+done
+
+for tree in tree emptytree; do
+    $bin pack_v0 $tmp/$tree.v0 dir=$tmp/$tree > /dev/null
+    $bin pack_v1 $tmp/$tree $tmp/$tree.v1
+done
+# and with checksums
+(cd $tmp/tree && find -type f -printf '%P\n' | $bin pack_v0 $tmp/checksums.v0 checksums > /dev/null)
+$bin pack_v1 $tmp/tree $tmp/checksums.v1 checksums
+$bin pack_v1 $tmp/tree $tmp/index.v1 index
+# xs is the only file big enough to get compressed
+$bin pack_v1 $tmp/tree $tmp/zstd.v1 checksums zstd
+$bin pack_v1 $tmp/tree $tmp/lz4.v1 index lz4
+$bin make_malicious $tmp/malicious.v0
+
+for target in decode_v0 unpack_v0 unpack_v0_ring unpack_v0_parallel; do
+    cp $tmp/tree.v0 $tmp/emptytree.v0 $tmp/checksums.v0 $tmp/malicious.v0 $corpus/$target/
+done
+for target in decode_v1 decode_v1_stream unpack_v1 unpack_v1_stream unpack_v1_ring unpack_v1_parallel; do
+    cp $tmp/tree.v1 $tmp/emptytree.v1 $tmp/checksums.v1 $tmp/index.v1 $tmp/zstd.v1 $tmp/lz4.v1 $corpus/$target/
+done
+# decode_index makes up its own header
+tail -c +9 $tmp/index.v1 > $corpus/decode_index/index
+
+ls -l $corpus/*
@@ -27,8 +27,7 @@ for dir in linux archive-testing; do
     fi
 
     if [ ! -f /tmp/$dir.v0 ]; then
-        cd /tmp/$dir.copy
-        $bin pack_v0 /tmp/$dir.v0 < <(find -type f -printf '%P\n')
+        $bin pack_v0 /tmp/$dir.v0 dir=/tmp/$dir.copy
     fi
 
 done
@@ -95,6 +94,9 @@ for dir in archive-testing linux; do
     #checkdest unpack_v0 "$bin unpack_v0 /tmp/$dir.v0 /tmp/dest"
     checkdest unpack_v1 "$bin unpack_v1 /tmp/$dir.v1 /tmp/dest"
     checkdest unpack_v1_ring "$bin unpack_v1_ring /tmp/$dir.v1 /tmp/dest"
+    checkdest unpack_v0_ring "$bin unpack_v0_ring /tmp/$dir.v0 /tmp/dest"
+    checkdest unpack_v0_parallel "$bin unpack_v0_parallel /tmp/$dir.v0 /tmp/dest"
+    checkdest unpack_v1_parallel "$bin unpack_v1_parallel /tmp/$dir.v1 /tmp/dest"
 done
 
 
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{Read,Seek,SeekFrom,Write};
+use std::io;
+
+use crate::common::Error;
+
+// reflected Castagnoli polynomial
+const POLY: u32 = 0x82f63b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC32C (Castagnoli), using the SSE4.2 crc32 instruction when the cpu has it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Crc32c {
+    // kept inverted between updates
+    state: u32,
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        Crc32c::default()
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        let crc = !self.state;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("sse4.2") {
+                self.state = !unsafe { update_sse42(crc, buf) };
+                return;
+            }
+        }
+        self.state = !update_table(crc, buf);
+    }
+
+    pub fn value(&self) -> u32 {
+        self.state
+    }
+}
+
+/// CRC32C of buf in one go
+pub fn crc32c(buf: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(buf);
+    crc.value()
+}
+
+fn update_table(mut crc: u32, buf: &[u8]) -> u32 {
+    for &b in buf {
+        crc = TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn update_sse42(crc: u32, buf: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64,_mm_crc32_u8};
+    let mut chunks = buf.chunks_exact(8);
+    let mut crc = crc as u64;
+    for chunk in &mut chunks {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &b in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, b);
+    }
+    crc
+}
+
+/// Checksum of the contents of file, read from its current position to the end
+pub fn crc32c_file(file: &mut File) -> Result<u32, Error> {
+    let mut crc = Crc32c::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let len = file.read(&mut buf).map_err(|_| Error::Read)?;
+        if len == 0 { break; }
+        crc.update(&buf[..len]);
+    }
+    Ok(crc.value())
+}
+
+/// Passes writes through to inner and keeps a checksum of everything written, for the trailer.
+/// Seeking is passed through too (so align_to_4 works) but anything other than asking for the
+/// current position would make the checksum meaningless
+pub struct CrcWriter<W> {
+    inner: W,
+    crc: Crc32c,
+}
+
+impl<W> CrcWriter<W> {
+    pub fn new(inner: W) -> CrcWriter<W> {
+        CrcWriter { inner, crc: Crc32c::new() }
+    }
+
+    pub fn into_parts(self) -> (W, u32) {
+        (self.inner, self.crc.value())
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for CrcWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        self.inner.stream_position()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the check value from the CRC catalogue, long enough to cover the 8 byte chunks of sse4.2
+    const CHECK: &[u8] = b"123456789";
+    const CHECK_CRC: u32 = 0xe3069283;
+
+    #[test]
+    fn known_answer() {
+        assert_eq!(crc32c(CHECK), CHECK_CRC);
+        assert_eq!(!update_table(!0, CHECK), CHECK_CRC);
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("sse4.2") {
+                assert_eq!(!unsafe { update_sse42(!0, CHECK) }, CHECK_CRC);
+            }
+        }
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn incremental() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let whole = crc32c(&data);
+        for split in [1, 7, 8, 9, 500, 999] {
+            let mut crc = Crc32c::new();
+            for chunk in data.chunks(split) {
+                crc.update(chunk);
+            }
+            assert_eq!(crc.value(), whole, "chunks of {split}");
+        }
+        let mut crc = Crc32c::new();
+        crc.update(&CHECK[..4]);
+        crc.update(&CHECK[4..]);
+        assert_eq!(crc.value(), CHECK_CRC);
+        assert_eq!(!update_table(update_table(!0, &CHECK[..4]), &CHECK[4..]), CHECK_CRC);
+    }
+}
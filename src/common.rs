@@ -1,4 +1,12 @@
 
+use std::fs::File;
+use std::io::{Seek,Write};
+use std::os::fd::AsRawFd;
+use std::ptr;
+
+use crate::compress::Codec;
+use crate::open::Confinement;
+
 #[derive(Debug)]
 pub enum Error {
     Getdents,
@@ -7,24 +15,105 @@ pub enum Error {
     Align,
     Open,
     Write,
-    #[allow(dead_code)]
+    Read,
     Statx,
     Fstat,
     DirTooDeep,
     Mkdirat,
+    Ring,
+    Mmap,
+    Chroot,
+    NotADir,
+    FileTooBig,
+    UnsafeName,
+    // the child of sandbox::sandboxed died without reporting a result
+    Child,
+    // extract_v0 found no file with that path
+    NotFound,
+    // the unpacker can't handle something in the archive, like compressed files in unpack_v1_ring
+    Unsupported,
+    Compress,
+    // from_tar couldn't parse its input
+    BadTar,
+    // header errors, see header.rs
+    BadMagic,
+    UnknownFormat { format: u8 },
+    UnknownVersion { version: u8 },
+    UnknownFlags { flags: u16 },
+    WrongFormat { expected: Format, found: Format },
+    // v1 decoding errors, offset is into the archive
+    BadTag { offset: usize, tag: u8 },
+    UnterminatedName { offset: usize },
+    NameTooLong { offset: usize },
+    Truncated { offset: usize },
+    UnbalancedPop { offset: usize },
+    MissingPop { offset: usize },
+    // v0 header errors, offset is into the archive
+    EmptyName { offset: usize },
+    NameCount { offset: usize, expected: usize, actual: usize },
+    BadPadding { offset: usize },
+    DataSize { offset: usize, expected: u64, actual: u64 },
+    // offset is of the entry that went over
+    LimitExceeded { offset: usize, limit: Limit },
+    // offset is of the stored checksum that didn't match
+    Checksum { offset: usize },
+    TrailingData { offset: usize },
+    // offset is of the index footer or entry that is out of bounds
+    BadIndex { offset: usize },
+    // compressed v1 files, offset is of the codec byte or the compressed data
+    UnknownCodec { offset: usize, codec: u8 },
+    Decompress { offset: usize },
+    // a converter was told to fail on entries it can't store, offset is of the entry in its input
+    Unrepresentable { offset: usize },
+    // from_cpio, offset is of the header, name or data that is malformed or out of bounds
+    BadCpio { offset: usize },
 }
 
-// from rustdocs
-pub fn read_le_u32(input: &mut &[u8]) -> u32 {
-    let (int_bytes, rest) = input.split_at(std::mem::size_of::<u32>());
-    *input = rest;
-    u32::from_le_bytes(int_bytes.try_into().unwrap())
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::BadTag { offset, tag } => write!(f, "bad tag byte {tag} at offset {offset}"),
+            Error::UnterminatedName { offset } => write!(f, "unterminated name at offset {offset}"),
+            Error::NameTooLong { offset } => write!(f, "name too long at offset {offset}"),
+            Error::Truncated { offset } => write!(f, "archive truncated at offset {offset}"),
+            Error::UnbalancedPop { offset } => write!(f, "pop without a dir at offset {offset}"),
+            Error::MissingPop { offset } => write!(f, "archive ended inside a dir at offset {offset}"),
+            Error::EmptyName { offset } => write!(f, "empty name at offset {offset}"),
+            Error::NameCount { offset, expected, actual } =>
+                write!(f, "name table at offset {offset} has {actual} names, header says {expected}"),
+            Error::BadPadding { offset } => write!(f, "non-zero padding byte at offset {offset}"),
+            Error::DataSize { offset, expected, actual } =>
+                write!(f, "file sizes sum to {expected} but there are {actual} bytes of data at offset {offset}"),
+            Error::BadMagic => write!(f, "bad magic, not an archive or a legacy one without a header"),
+            Error::UnknownFormat { format } => write!(f, "unknown format {format}"),
+            Error::UnknownVersion { version } => write!(f, "unknown format version {version}"),
+            Error::UnknownFlags { flags } => write!(f, "unknown required flags {flags:#x}"),
+            Error::WrongFormat { expected, found } => write!(f, "expected a {expected:?} archive but found {found:?}"),
+            Error::Checksum { offset } => write!(f, "checksum mismatch at offset {offset}"),
+            Error::TrailingData { offset } => write!(f, "unexpected data after the end of the archive at offset {offset}"),
+            Error::LimitExceeded { offset, limit } => write!(f, "{limit:?} limit exceeded at offset {offset}"),
+            Error::BadIndex { offset } => write!(f, "bad index at offset {offset}"),
+            Error::UnknownCodec { offset, codec } => write!(f, "unknown compression {codec} at offset {offset}"),
+            Error::Decompress { offset } => write!(f, "corrupt compressed data at offset {offset}"),
+            Error::Unrepresentable { offset } =>
+                write!(f, "entry at offset {offset} of the input is not a regular file or dir"),
+            Error::BadTar => write!(f, "bad tar archive"),
+            Error::BadCpio { offset } => write!(f, "bad cpio archive at offset {offset}"),
+            Error::Unsupported => write!(f, "archive uses a feature this unpacker doesn't support"),
+            Error::NotFound => write!(f, "no file with that path in the archive"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
 }
 
 pub enum ArchiveFormat1Tag {
     File = 1,
     Dir = 2,
     Pop = 3,
+    // ends the messages when anything follows them, see Header::has_end
+    End = 4,
+    // revision 2, see pack_v1
+    Compressed = 5,
 }
 
 impl TryFrom<&u8> for ArchiveFormat1Tag {
@@ -35,7 +124,255 @@ impl TryFrom<&u8> for ArchiveFormat1Tag {
             1 => Ok(ArchiveFormat1Tag::File),
             2 => Ok(ArchiveFormat1Tag::Dir),
             3 => Ok(ArchiveFormat1Tag::Pop),
+            4 => Ok(ArchiveFormat1Tag::End),
+            5 => Ok(ArchiveFormat1Tag::Compressed),
             _ => Err(()),
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    V0,
+    V1,
+}
+
+/// What was packed or unpacked
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub dirs: usize,
+    pub files: usize,
+    /// total bytes of file contents
+    pub data_bytes: u64,
+    /// total bytes of names, including zero terminators
+    pub name_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct UnpackOptions {
+    /// v0 only: copy file contents with copy_file_range from the archive instead of write from
+    /// the mmap
+    pub copy_file_range: bool,
+    pub confinement: Confinement,
+    pub limits: UnpackLimits,
+    /// read archives from before the header existed (see header.rs), which are just the body of
+    /// whichever format the unpack function is for
+    pub legacy: bool,
+    /// decode and check everything, including checksums, but don't create anything. outdir is
+    /// ignored
+    pub verify_only: bool,
+    /// ring unpackers only
+    pub ring: RingOptions,
+    /// parallel unpackers only: how many threads create files. 0 is one per cpu (as far as
+    /// available_parallelism knows)
+    pub threads: usize,
+}
+
+/// Setup of the ring unpackers (unpack_v0_ring and unpack_v1_ring). The setup flags go to the
+/// kernel as asked, one that doesn't take them fails the unpack with Error::Ring. See
+/// ring_support for which it takes
+#[derive(Debug, Default, Clone)]
+pub struct RingOptions {
+    /// how many ops (a file's open and writes, a dir's mkdir and open, a close) can be
+    /// outstanding at once, which is also the number of fixed file slots. 0 is
+    /// DEFAULT_RING_DEPTH, anything over 16384 is capped
+    pub depth: usize,
+    /// register the archive as fixed buffers and write with WriteFixed. Pinning needs a writable
+    /// mapping, so the archive is mapped private and its pages are copied as they are pinned,
+    /// which counts against RLIMIT_MEMLOCK. For root too with Confinement::Chroot and sqpoll
+    pub fixed_buffers: bool,
+    /// IORING_SETUP_SQPOLL, a kernel thread takes sqes off the ring so submitting mostly needs
+    /// no syscall
+    pub sqpoll: bool,
+    /// IORING_SETUP_SINGLE_ISSUER
+    pub single_issuer: bool,
+    /// IORING_SETUP_DEFER_TASKRUN, completions only run when we wait for them. Needs
+    /// single_issuer and can't go with sqpoll
+    pub defer_taskrun: bool,
+    /// IORING_SETUP_COOP_TASKRUN, can't go with sqpoll
+    pub coop_taskrun: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PackOptions {
+    /// store a CRC32C per file and one for the whole archive (see header.rs)
+    pub checksums: bool,
+    /// v1 only, append an index of every path (see index.rs)
+    pub index: bool,
+    /// v1 only, compress the files that are worth it (see pack_v1)
+    pub compression: Option<Codec>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    TotalBytes,
+    FileSize,
+    Files,
+    Dirs,
+    NameLen,
+    Depth,
+}
+
+/// Caps for unpacking untrusted archives, each is an inclusive maximum and the default is
+/// unlimited. v0 checks everything against the header before creating anything, v1 checks the
+/// running totals before each entry is created.
+#[derive(Debug, Clone)]
+pub struct UnpackLimits {
+    /// sum of all file sizes
+    pub max_total_bytes: u64,
+    pub max_file_size: u64,
+    pub max_files: usize,
+    pub max_dirs: usize,
+    /// of a single path component (v0 names are whole paths so are split on /)
+    pub max_name_len: usize,
+    /// number of nested dirs, so a file at the top level is depth 0 and a/b/c is depth 2
+    pub max_depth: usize,
+}
+
+impl Default for UnpackLimits {
+    fn default() -> UnpackLimits {
+        UnpackLimits {
+            max_total_bytes: u64::MAX,
+            max_file_size: u64::MAX,
+            max_files: usize::MAX,
+            max_dirs: usize::MAX,
+            max_name_len: usize::MAX,
+            max_depth: usize::MAX,
+        }
+    }
+}
+
+// running totals checked against UnpackLimits as entries are decoded
+pub(crate) struct Limiter {
+    limits: UnpackLimits,
+    files: usize,
+    dirs: usize,
+    total_bytes: u64,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: UnpackLimits) -> Limiter {
+        Limiter { limits, files: 0, dirs: 0, total_bytes: 0 }
+    }
+
+    fn check(ok: bool, limit: Limit, offset: usize) -> Result<(), Error> {
+        if ok { Ok(()) } else { Err(Error::LimitExceeded { offset, limit }) }
+    }
+
+    fn name(&self, name: &[u8], offset: usize) -> Result<(), Error> {
+        for component in name.split(|&b| b == b'/') {
+            Self::check(component.len() <= self.limits.max_name_len, Limit::NameLen, offset)?;
+        }
+        Ok(())
+    }
+
+    /// depth is the number of dirs the file is inside
+    pub(crate) fn file(&mut self, name: &[u8], size: u64, depth: usize, offset: usize) -> Result<(), Error> {
+        self.name(name, offset)?;
+        Self::check(depth <= self.limits.max_depth, Limit::Depth, offset)?;
+        Self::check(size <= self.limits.max_file_size, Limit::FileSize, offset)?;
+        self.files += 1;
+        Self::check(self.files <= self.limits.max_files, Limit::Files, offset)?;
+        self.total_bytes = self.total_bytes.saturating_add(size);
+        Self::check(self.total_bytes <= self.limits.max_total_bytes, Limit::TotalBytes, offset)
+    }
+
+    /// depth is the number of dirs the new dir is inside
+    pub(crate) fn dir(&mut self, name: &[u8], depth: usize, offset: usize) -> Result<(), Error> {
+        self.name(name, offset)?;
+        Self::check(depth <= self.limits.max_depth, Limit::Depth, offset)?;
+        self.dirs += 1;
+        Self::check(self.dirs <= self.limits.max_dirs, Limit::Dirs, offset)
+    }
+}
+
+pub fn align_to_4<W: Seek + Write>(writer: &mut W) -> Result<(), Error> {
+    let pos = writer.stream_position().map_err(|_| Error::Align)?;
+    if pos % 4 == 0 { return Ok(()); }
+    let adj = 4 - (pos % 4);
+    for _ in 0..adj { writer.write_all(&[0]).map_err(|_| Error::Align)?; }
+    let pos = writer.stream_position().map_err(|_| Error::Align)?;
+    assert!(pos % 4 == 0);
+    Ok(())
+}
+
+// the File::metadata.len() uses statx with STATX_ALL
+// the statx struct is much bigger than stat and even with masking
+// it still does a copy of the whole thing
+#[allow(dead_code)]
+pub fn file_size_statx<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
+    use std::mem;
+    let size = unsafe {
+        let empty_path = c"";
+        let mut buf: libc::statx = mem::zeroed();
+        let ret = libc::statx(
+            fd.as_raw_fd(), empty_path.as_ptr(),
+            libc::AT_STATX_SYNC_AS_STAT | libc::AT_EMPTY_PATH,
+            libc::STATX_SIZE,
+            &mut buf as *mut _
+        );
+        if ret < 0 { return Err(Error::Statx); }
+        buf.stx_size
+    };
+    Ok(size)
+}
+
+pub fn file_size_fstat<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
+    use std::mem;
+    let size = unsafe {
+        let mut buf: libc::stat = mem::zeroed();
+        let ret = libc::fstat(
+            fd.as_raw_fd(),
+            &mut buf as *mut _
+        );
+        if ret < 0 { return Err(Error::Fstat); }
+        buf.st_size
+    };
+    // dude st_size is signed here and unsigned in statx
+    size.try_into().map_err(|_| Error::Fstat)
+}
+
+pub fn file_size<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
+    //file_size_statx(fd)
+    file_size_fstat(fd)
+}
+
+pub fn copy_file_range_all(filein: &File, fileout: &File, len: u64) -> Result<(), Error> {
+    let fd_in  = filein.as_raw_fd();
+    let fd_out = fileout.as_raw_fd();
+    let mut len = len;
+    while len > 0 {
+        let ret = unsafe {
+            libc::copy_file_range(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len as usize, 0)
+        };
+        if ret <= 0 { return Err(Error::CopyFileRange); }
+        let ret = ret as u64;
+        if ret > len { return Err(Error::CopyFileRange); }
+        len -= ret;
+    }
+    Ok(())
+}
+
+pub fn sendfile_all<Fd: AsRawFd>(filein: &File, fileout: &Fd, len: u64) -> Result<(), Error> {
+    let fd_in  = filein.as_raw_fd();
+    let fd_out = fileout.as_raw_fd();
+    let mut len = len;
+    while len > 0 {
+        let ret = unsafe {
+            libc::sendfile(fd_out, fd_in, ptr::null_mut(), len as usize)
+        };
+        if ret <= 0 { return Err(Error::CopyFileRange); }
+        let ret = ret as u64;
+        if ret > len { return Err(Error::CopyFileRange); }
+        len -= ret;
+    }
+    Ok(())
+}
+
+// mmap of an empty file fails with EINVAL but an empty file is a valid (empty) v1 archive, so
+// that maps to None and callers use an empty slice
+pub fn mmap_file(file: &File) -> Result<Option<memmap::Mmap>, Error> {
+    if file_size(file)? == 0 { return Ok(None); }
+    let mmap = unsafe { memmap::MmapOptions::new().map(file) }.map_err(|_| Error::Mmap)?;
+    Ok(Some(mmap))
+}
@@ -0,0 +1,129 @@
+use std::ffi::OsStr;
+use std::io::{BufRead,Read,Write};
+use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
+
+use crate::checksum::Crc32c;
+use crate::common::Error;
+
+const ZSTD_LEVEL: i32 = 3;
+
+// smaller files don't win back the frame overhead
+const MIN_COMPRESS_LEN: u64 = 64;
+
+// compressed has to come out at least this much smaller (as a fraction of the original) or the
+// file is stored as is
+const MIN_SAVING: u64 = 16;
+
+// extensions of formats that are already compressed, packing these just burns cpu
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "gz", "tgz", "bz2", "xz", "txz", "zst", "lz4", "lz", "lzma", "zip", "jar", "whl", "7z", "rar",
+    "png", "jpg", "jpeg", "gif", "webp", "avif", "mp3", "mp4", "mkv", "webm", "ogg", "woff", "woff2",
+];
+
+/// How a compressed v1 file is stored, the value is its byte in the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ();
+    fn try_from(x: u8) -> Result<Codec, ()> {
+        match x {
+            1 => Ok(Codec::Zstd),
+            2 => Ok(Codec::Lz4),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a file is worth trying to compress, from its name and size alone
+pub fn worth_compressing(name: &[u8], len: u64) -> bool {
+    if len < MIN_COMPRESS_LEN { return false; }
+    let extension = Path::new(OsStr::from_bytes(name))
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase());
+    match extension {
+        Some(extension) => !COMPRESSED_EXTENSIONS.contains(&extension.as_str()),
+        None => true,
+    }
+}
+
+/// data compressed with codec, or None if that didn't save enough to be worth it
+pub fn compress(codec: Codec, data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let compressed = match codec {
+        Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).map_err(|_| Error::Compress)?,
+        Codec::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+            encoder.write_all(data).map_err(|_| Error::Compress)?;
+            encoder.finish().map_err(|_| Error::Compress)?
+        },
+    };
+    let len = data.len() as u64;
+    if compressed.len() as u64 > len - len / MIN_SAVING { return Ok(None); }
+    Ok(Some(compressed))
+}
+
+/// Decompresses input, which holds one codec frame, into out through a small buffer and returns
+/// the CRC32C of what was written. Decompressing stops one byte past size so a bomb can't run
+/// away, anything other than exactly size bytes out is Decompress at offset
+pub fn decompress<R: BufRead, W: Write>(codec: Codec, input: R, size: u64, out: &mut W, offset: usize) -> Result<u32, Error> {
+    match codec {
+        Codec::Zstd => {
+            let decoder = zstd::stream::read::Decoder::with_buffer(input).map_err(|_| Error::Decompress { offset })?;
+            copy_exact(decoder, size, out, offset)
+        },
+        Codec::Lz4 => copy_exact(lz4_flex::frame::FrameDecoder::new(input), size, out, offset),
+    }
+}
+
+fn copy_exact<R: Read, W: Write>(decoder: R, size: u64, out: &mut W, offset: usize) -> Result<u32, Error> {
+    let mut decoder = decoder.take(size + 1);
+    let mut crc = Crc32c::new();
+    let mut buf = vec![0u8; 1 << 16];
+    let mut copied = 0;
+    loop {
+        let n = decoder.read(&mut buf).map_err(|_| Error::Decompress { offset })?;
+        if n == 0 { break; }
+        copied += n as u64;
+        if copied > size { return Err(Error::Decompress { offset }); }
+        crc.update(&buf[..n]);
+        out.write_all(&buf[..n]).map_err(|_| Error::Write)?;
+    }
+    if copied != size { return Err(Error::Decompress { offset }); }
+    Ok(crc.value())
+}
+
+/// The stored bytes of a compressed v1 file, still to be decompressed
+#[derive(Debug, Clone, Copy)]
+pub struct Compressed<'a> {
+    pub codec: Codec,
+    /// decompressed size
+    pub size: u64,
+    pub data: &'a [u8],
+    /// CRC32C of the decompressed data, with FLAG_FILE_CHECKSUMS. It can only be checked by
+    /// decompressing, so decompress_to does
+    pub crc: Option<u32>,
+    // of data, for errors
+    pub(crate) offset: usize,
+}
+
+impl Compressed<'_> {
+    pub fn decompress_to<W: Write>(&self, out: &mut W) -> Result<(), Error> {
+        let crc = decompress(self.codec, self.data, self.size, out, self.offset)?;
+        match self.crc {
+            Some(expected) if expected != crc => Err(Error::Checksum { offset: self.offset + self.data.len() }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        // size isn't trusted enough to allocate up front
+        let mut out = Vec::new();
+        self.decompress_to(&mut out)?;
+        Ok(out)
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::ffi::{CString,OsStr};
+use std::fs::File;
+use std::os::unix::prelude::OsStrExt;
+use std::path::PathBuf;
+
+use crate::common::{Error,Format,PackOptions,Stats};
+use crate::v0::pack_v0_entries;
+use crate::v1::pack_v1_tree;
+
+/// What an entry that a converter couldn't store was
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkippedKind {
+    Symlink,
+    Hardlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Sparse,
+    Other,
+}
+
+/// An entry of the input that isn't a regular file or dir, which v0 and v1 have no way to store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub kind: SkippedKind,
+    /// of the entry in the input
+    pub offset: usize,
+}
+
+/// What to do with entries that can't be stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnSkipped {
+    /// leave them out and list them in Converted::skipped
+    #[default]
+    Report,
+    /// fail with Error::Unrepresentable
+    Fail,
+}
+
+/// The result of converting another format to v0 or v1
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Converted {
+    pub stats: Stats,
+    pub skipped: Vec<Skipped>,
+}
+
+pub(crate) enum Node<'a> {
+    // contents borrowed from the input, which is usually mmapped
+    File(&'a [u8]),
+    Dir(Tree<'a>),
+}
+
+/// The files and dirs read out of another format, which can be in any order and name a path
+/// more than once, so they are collected here before writing v0 or v1. A later entry replaces an
+/// earlier one with the same path, like extracting would, and dirs are created for every parent
+#[derive(Default)]
+pub(crate) struct Tree<'a> {
+    pub(crate) entries: BTreeMap<CString, Node<'a>>,
+}
+
+impl<'a> Tree<'a> {
+    /// path is relative; leading /'s and . components are dropped, and .. is UnsafeName
+    pub(crate) fn insert_file(&mut self, path: &[u8], data: &'a [u8]) -> Result<(), Error> {
+        let (parent, name) = self.parent_of(path)?;
+        let name = name.ok_or(Error::UnsafeName)?;
+        parent.entries.insert(name, Node::File(data));
+        Ok(())
+    }
+
+    pub(crate) fn insert_dir(&mut self, path: &[u8]) -> Result<(), Error> {
+        let (parent, name) = self.parent_of(path)?;
+        // the root itself, eg ./ at the start of most tarballs
+        let Some(name) = name else { return Ok(()) };
+        parent.dir(name);
+        Ok(())
+    }
+
+    // the dir to put the last component of path in (creating it) and that component
+    fn parent_of(&mut self, path: &[u8]) -> Result<(&mut Tree<'a>, Option<CString>), Error> {
+        let mut names = Vec::new();
+        for component in path.split(|&b| b == b'/') {
+            match component {
+                b"" | b"." => {},
+                b".." => return Err(Error::UnsafeName),
+                _ => names.push(CString::new(component).map_err(|_| Error::UnsafeName)?),
+            }
+        }
+        let last = names.pop();
+        let mut tree = self;
+        for name in names {
+            tree = tree.dir(name);
+        }
+        Ok((tree, last))
+    }
+
+    // the dir called name, replacing a file if there is one
+    fn dir(&mut self, name: CString) -> &mut Tree<'a> {
+        let node = self.entries.entry(name).or_insert_with(|| Node::Dir(Tree::default()));
+        if let Node::File(_) = node {
+            *node = Node::Dir(Tree::default());
+        }
+        match node {
+            Node::Dir(tree) => tree,
+            Node::File(_) => unreachable!(),
+        }
+    }
+
+    // full paths of every dir (parents first) and file, for v0
+    fn flatten(&self, prefix: &mut Vec<u8>, dirs: &mut Vec<Vec<u8>>, files: &mut Vec<(Vec<u8>, &'a [u8])>) {
+        for (name, node) in &self.entries {
+            let len = prefix.len();
+            if len != 0 { prefix.push(b'/'); }
+            prefix.extend_from_slice(name.as_bytes());
+            match node {
+                Node::File(data) => files.push((prefix.clone(), data)),
+                Node::Dir(tree) => {
+                    dirs.push(prefix.clone());
+                    tree.flatten(prefix, dirs, files);
+                },
+            }
+            prefix.truncate(len);
+        }
+    }
+
+    pub(crate) fn pack(&self, format: Format, out: File, opts: &PackOptions) -> Result<Stats, Error> {
+        match format {
+            Format::V0 => {
+                let (mut dirs, mut files) = (vec![], vec![]);
+                self.flatten(&mut vec![], &mut dirs, &mut files);
+                let dirs: Vec<&[u8]> = dirs.iter().map(|x| x.as_slice()).collect();
+                let files: Vec<(&[u8], &[u8])> = files.iter().map(|(name, data)| (name.as_slice(), *data)).collect();
+                pack_v0_entries(out, opts, &dirs, &files)
+            },
+            Format::V1 => pack_v1_tree(self, out, opts),
+        }
+    }
+}
+
+// records an entry that can't be stored, or fails on it
+pub(crate) fn skip(skipped: &mut Vec<Skipped>, on_skipped: OnSkipped, path: &[u8], kind: SkippedKind, offset: usize) -> Result<(), Error> {
+    if on_skipped == OnSkipped::Fail { return Err(Error::Unrepresentable { offset }); }
+    skipped.push(Skipped { path: OsStr::from_bytes(path).into(), kind, offset });
+    Ok(())
+}
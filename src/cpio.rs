@@ -0,0 +1,189 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufWriter,Read,Write};
+use std::path::Path;
+
+use crate::common::{Error,Format,PackOptions,Stats,file_size};
+use crate::convert::{Converted,OnSkipped,SkippedKind,Tree,skip};
+use crate::liblistdir::{Visitor,Walk,list_dir};
+use crate::v1::Paths;
+
+const MAGIC: &[u8; 6] = b"070701";
+// same layout, the check field of a regular file is the sum of its data bytes
+const MAGIC_CRC: &[u8; 6] = b"070702";
+// offset of the check field in the header
+const CHECK_OFFSET: usize = 6 + 12 * 8;
+const HEADER_LEN: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+const S_IFSOCK: u32 = 0o140000;
+
+/// cpio newc header, after the magic every field is 8 hex digits
+/// ino mode uid gid nlink mtime filesize devmajor devminor rdevmajor rdevminor namesize check
+/// followed by the name (namesize includes its zero terminator) padded so header + name is a
+/// multiple of 4, then the data padded to a multiple of 4. The last entry is named TRAILER!!!
+struct NewcHeader {
+    mode: u32,
+    nlink: u32,
+    filesize: u32,
+    namesize: u32,
+    // for MAGIC_CRC
+    check: Option<u32>,
+}
+
+fn parse_hex(field: &[u8], offset: usize) -> Result<u32, Error> {
+    std::str::from_utf8(field).ok()
+        .and_then(|x| u32::from_str_radix(x, 16).ok())
+        .ok_or(Error::BadCpio { offset })
+}
+
+impl NewcHeader {
+    fn parse(buf: &[u8], offset: usize) -> Result<NewcHeader, Error> {
+        let bytes = buf.get(offset..offset + HEADER_LEN).ok_or(Error::BadCpio { offset })?;
+        if &bytes[..6] != MAGIC && &bytes[..6] != MAGIC_CRC { return Err(Error::BadCpio { offset }); }
+        let field = |i: usize| parse_hex(&bytes[6 + i * 8..14 + i * 8], offset + 6 + i * 8);
+        let check = if &bytes[..6] == MAGIC_CRC { Some(field(12)?) } else { None };
+        Ok(NewcHeader { mode: field(1)?, nlink: field(4)?, filesize: field(6)?, namesize: field(11)?, check })
+    }
+}
+
+/// Converts a cpio archive in the newc format (what the kernel takes for an initramfs) in buf to
+/// a v0 or v1 archive in out, with the file contents borrowed from buf. Like from_tar only
+/// regular files and dirs are stored and the rest is reported or fails with on_skipped. newc
+/// stores the data of hardlinked files once, with the last link, so files with more than one link
+/// are reported as hardlinks. The checksums of the 070702 variant are checked, a regular file
+/// whose data doesn't add up to its check field is BadCpio
+pub fn from_cpio(buf: &[u8], format: Format, out: File, opts: &PackOptions, on_skipped: OnSkipped) -> Result<Converted, Error> {
+    let mut tree = Tree::default();
+    let mut skipped = vec![];
+    let mut offset = 0;
+    loop {
+        let header = NewcHeader::parse(buf, offset)?;
+        let name_start = offset + HEADER_LEN;
+        let name = name_start.checked_add(header.namesize as usize)
+            .and_then(|end| buf.get(name_start..end))
+            .and_then(|name| CStr::from_bytes_with_nul(name).ok())
+            .ok_or(Error::BadCpio { offset: name_start })?
+            .to_bytes();
+        let data_start = (name_start + header.namesize as usize).next_multiple_of(4);
+        let data = data_start.checked_add(header.filesize as usize)
+            .and_then(|end| buf.get(data_start..end))
+            .ok_or(Error::BadCpio { offset: data_start })?;
+        if name == TRAILER { break; }
+        let entry_offset = offset;
+        offset = (data_start + data.len()).next_multiple_of(4);
+        let kind = match header.mode & S_IFMT {
+            S_IFREG if header.nlink > 1 => SkippedKind::Hardlink,
+            S_IFREG => {
+                if let Some(check) = header.check {
+                    let sum = data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+                    if sum != check { return Err(Error::BadCpio { offset: entry_offset + CHECK_OFFSET }); }
+                }
+                tree.insert_file(name, data)?;
+                continue;
+            },
+            S_IFDIR => {
+                tree.insert_dir(name)?;
+                continue;
+            },
+            S_IFLNK => SkippedKind::Symlink,
+            S_IFCHR => SkippedKind::CharDevice,
+            S_IFBLK => SkippedKind::BlockDevice,
+            S_IFIFO => SkippedKind::Fifo,
+            S_IFSOCK => SkippedKind::Socket,
+            _ => SkippedKind::Other,
+        };
+        skip(&mut skipped, on_skipped, name, kind, entry_offset)?;
+    }
+    let stats = tree.pack(format, out, opts)?;
+    Ok(Converted { stats, skipped })
+}
+
+// Writes each entry as list_dir finds it, so dirs come before what is in them like cpio -i
+// wants. Files are 644 and dirs 755, owned by root with a zero mtime, as v0 and v1 have none of
+// that to carry over
+struct NewcWriter<W: Write> {
+    writer: BufWriter<W>,
+    stats: Stats,
+    paths: Paths,
+    // inode numbers just have to be unique, cpio -i uses them to find hardlinks
+    ino: u32,
+}
+
+impl<W: Write> NewcWriter<W> {
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(bytes).map_err(|_| Error::Write)
+    }
+
+    fn emit_header(&mut self, path: &[u8], ino: u32, mode: u32, nlink: u32, filesize: u64) -> Result<(), Error> {
+        if filesize > u32::MAX as u64 { return Err(Error::FileTooBig); }
+        let namesize = path.len() as u32 + 1;
+        let fields = [ino, mode, 0, 0, nlink, 0, filesize as u32, 0, 0, 0, 0, namesize, 0];
+        let mut header = Vec::with_capacity(HEADER_LEN + path.len() + 4);
+        header.extend_from_slice(MAGIC);
+        for field in fields {
+            write!(header, "{field:08x}").unwrap();
+        }
+        header.extend_from_slice(path);
+        header.push(0);
+        header.resize(header.len().next_multiple_of(4), 0);
+        self.emit(&header)
+    }
+
+    fn pad(&mut self, len: u64) -> Result<(), Error> {
+        let padding = (len.next_multiple_of(4) - len) as usize;
+        self.emit(&[0; 3][..padding])
+    }
+}
+
+impl<W: Write> Visitor for NewcWriter<W> {
+    type Error = Error;
+
+    fn on_file(&mut self, name: &CStr, file: File) -> Result<(), Error> {
+        let len = file_size(&file)?;
+        self.stats.files += 1;
+        self.stats.data_bytes += len;
+        self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+        let path = self.paths.push(name.to_bytes()).to_vec();
+        self.paths.pop();
+        self.ino += 1;
+        self.emit_header(&path, self.ino, S_IFREG | 0o644, 1, len)?;
+        let copied = std::io::copy(&mut (&file).take(len), &mut self.writer).map_err(|_| Error::Write)?;
+        // the file shrank since we got its size
+        if copied != len { return Err(Error::Read); }
+        self.pad(len)
+    }
+
+    fn on_dir(&mut self, name: &CStr) -> Result<Walk, Error> {
+        self.stats.dirs += 1;
+        self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+        let path = self.paths.push(name.to_bytes()).to_vec();
+        self.ino += 1;
+        self.emit_header(&path, self.ino, S_IFDIR | 0o755, 2, 0)?;
+        Ok(Walk::Continue)
+    }
+
+    fn leave_dir(&mut self) -> Result<(), Error> {
+        self.paths.pop();
+        Ok(())
+    }
+}
+
+/// Packs the tree under indir as a cpio newc archive into out, for comparing against v0 and v1
+/// without GNU cpio (which also needs every parent dir listed before its files). Only regular
+/// files and dirs are packed. Stats are counted like pack_v1 does
+pub fn pack_cpio<W: Write>(indir: &Path, out: W) -> Result<Stats, Error> {
+    if !indir.is_dir() { return Err(Error::NotADir); }
+    let mut writer = NewcWriter { writer: BufWriter::new(out), stats: Stats::default(), paths: Paths::default(), ino: 0 };
+    list_dir(indir, &mut writer)?;
+    writer.emit_header(TRAILER, 0, 0, 1, 0)?;
+    writer.writer.flush().map_err(|_| Error::Write)?;
+    Ok(writer.stats)
+}
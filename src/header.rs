@@ -0,0 +1,212 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::path::Path;
+
+use crate::checksum::crc32c;
+use crate::common::{Error,Format,PackOptions,Stats,UnpackOptions,mmap_file};
+use crate::ioringv1::{RingMmap,mmap_for_ring};
+use crate::v0::{Archive,extract_v0,unpack_v0_buf};
+use crate::v1::{Decoder,extract_v1,unpack_v1_buf};
+
+/// Every archive starts with an 8 byte header so the formats can be told apart
+/// magic: "ATAR"
+/// format: u8, 0 for v0 and 1 for v1
+/// version: u8, revision of the formats, MIN_VERSION to VERSION
+/// flags: u16le, low byte is features a reader must understand (it errors on unknown ones), high
+///        byte is features it can ignore
+/// then the body of the format. 8 bytes keeps the v0 size table 4 byte aligned.
+/// Archives from before the header existed are read with UnpackOptions::legacy and are treated as
+/// LEGACY_VERSION with no flags.
+///
+/// With FLAG_TRAILER_CHECKSUM the archive ends with a u32le CRC32C of every byte before it, header
+/// included. See the formats for FLAG_FILE_CHECKSUMS.
+///
+/// v1 ends its messages with an End tag when anything follows them (see has_end). Whatever is
+/// between End and the trailer belongs to optional flags (FLAG_INDEX, see index.rs), so a reader
+/// that doesn't know one still finds the end of the messages and skips the rest
+pub const MAGIC: [u8; 4] = *b"ATAR";
+pub const HEADER_LEN: usize = 8;
+/// Revision 2 added compressed v1 files. Packers write the oldest revision that has everything
+/// they used, so archives without compression are still readable by revision 1 readers
+pub const VERSION: u8 = 2;
+pub const MIN_VERSION: u8 = 1;
+pub const COMPRESSION_VERSION: u8 = 2;
+pub const LEGACY_VERSION: u8 = 0;
+pub const TRAILER_LEN: usize = 4;
+
+pub const FLAG_FILE_CHECKSUMS: u16 = 1 << 0;
+pub const FLAG_TRAILER_CHECKSUM: u16 = 1 << 1;
+pub const FLAG_INDEX: u16 = 1 << 8;
+
+const KNOWN_REQUIRED_FLAGS: u16 = FLAG_FILE_CHECKSUMS | FLAG_TRAILER_CHECKSUM;
+const REQUIRED_FLAGS_MASK: u16 = 0x00ff;
+const OPTIONAL_FLAGS_MASK: u16 = 0xff00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub format: Format,
+    pub version: u8,
+    pub flags: u16,
+}
+
+impl Header {
+    pub fn new(format: Format) -> Header {
+        Header { format, version: MIN_VERSION, flags: 0 }
+    }
+
+    /// The header a packer writes for opts
+    pub fn for_pack(format: Format, opts: &PackOptions) -> Header {
+        let mut header = Header::new(format);
+        if opts.checksums {
+            header.flags |= FLAG_FILE_CHECKSUMS | FLAG_TRAILER_CHECKSUM;
+        }
+        if opts.index && format == Format::V1 {
+            header.flags |= FLAG_INDEX;
+        }
+        if opts.compression.is_some() && format == Format::V1 {
+            header.version = COMPRESSION_VERSION;
+        }
+        header
+    }
+
+    pub fn legacy(format: Format) -> Header {
+        Header { format, version: LEGACY_VERSION, flags: 0 }
+    }
+
+    /// Where the body of the format starts, legacy archives have no header
+    pub fn body_start(&self) -> usize {
+        if self.version == LEGACY_VERSION { 0 } else { HEADER_LEN }
+    }
+
+    pub fn file_checksums(&self) -> bool {
+        self.flags & FLAG_FILE_CHECKSUMS != 0
+    }
+
+    pub fn trailer_checksum(&self) -> bool {
+        self.flags & FLAG_TRAILER_CHECKSUM != 0
+    }
+
+    /// Whether v1 files can be Compressed messages
+    pub fn compression(&self) -> bool {
+        self.version >= COMPRESSION_VERSION
+    }
+
+    pub fn index(&self) -> bool {
+        self.flags & FLAG_INDEX != 0
+    }
+
+    /// Whether there may be optional blocks between End and the trailer
+    pub fn optional_blocks(&self) -> bool {
+        self.flags & OPTIONAL_FLAGS_MASK != 0
+    }
+
+    /// Whether v1 messages are followed by End
+    pub fn has_end(&self) -> bool {
+        self.trailer_checksum() || self.optional_blocks()
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Header, Error> {
+        if buf.get(..MAGIC.len()) != Some(&MAGIC[..]) { return Err(Error::BadMagic); }
+        let bytes = buf.get(..HEADER_LEN).ok_or(Error::Truncated { offset: MAGIC.len() })?;
+        let format = match bytes[4] {
+            0 => Format::V0,
+            1 => Format::V1,
+            format => return Err(Error::UnknownFormat { format }),
+        };
+        let version = bytes[5];
+        if !(MIN_VERSION..=VERSION).contains(&version) { return Err(Error::UnknownVersion { version }); }
+        let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+        let unknown = flags & REQUIRED_FLAGS_MASK & !KNOWN_REQUIRED_FLAGS;
+        if unknown != 0 { return Err(Error::UnknownFlags { flags: unknown }); }
+        Ok(Header { format, version, flags })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let format = match self.format {
+            Format::V0 => 0,
+            Format::V1 => 1,
+        };
+        let flags = self.flags.to_le_bytes();
+        [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], format, self.version, flags[0], flags[1]]
+    }
+
+    pub fn expect(&self, format: Format) -> Result<(), Error> {
+        if self.format != format {
+            return Err(Error::WrongFormat { expected: format, found: self.format });
+        }
+        Ok(())
+    }
+}
+
+/// The checked header of a format archive, or Header::legacy for legacy headerless archives
+pub fn read_header(buf: &[u8], format: Format, legacy: bool) -> Result<Header, Error> {
+    if legacy { return Ok(Header::legacy(format)); }
+    let header = Header::parse(buf)?;
+    header.expect(format)?;
+    Ok(header)
+}
+
+/// Checks the trailer checksum if the header says there is one, the unpackers that have the whole
+/// archive call this before writing anything
+pub fn verify_trailer(buf: &[u8], header: &Header) -> Result<(), Error> {
+    if !header.trailer_checksum() { return Ok(()); }
+    let offset = buf.len().checked_sub(TRAILER_LEN)
+        .filter(|&offset| offset >= header.body_start())
+        .ok_or(Error::Truncated { offset: buf.len() })?;
+    let expected = u32::from_le_bytes(buf[offset..].try_into().unwrap());
+    if crc32c(&buf[..offset]) != expected { return Err(Error::Checksum { offset }); }
+    Ok(())
+}
+
+// What every unpacker that maps the whole archive starts with: map_archive (or
+// map_archive_for_ring), then open_v0 or open_v1 on the mapping, then return early with the stats
+// for opts.verify_only. The mapping has to outlive what open_* returns so it can't be one call
+pub(crate) fn map_archive(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Option<memmap::Mmap>, Error> {
+    if !opts.verify_only && !outdir.is_dir() { return Err(Error::NotADir); }
+    mmap_file(infile)
+}
+
+// see mmap_for_ring
+pub(crate) fn map_archive_for_ring(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Option<RingMmap>, Error> {
+    if !opts.verify_only && !outdir.is_dir() { return Err(Error::NotADir); }
+    mmap_for_ring(infile, &opts.ring)
+}
+
+// the header, trailer, tables, limits and checksums of a v0 archive, all checked
+pub(crate) fn open_v0<'a>(buf: &'a [u8], opts: &UnpackOptions) -> Result<Archive<'a>, Error> {
+    let header = read_header(buf, Format::V0, opts.legacy)?;
+    verify_trailer(buf, &header)?;
+    let archive = Archive::parse_with(buf, &header)?;
+    archive.check_limits(&opts.limits)?;
+    archive.verify_checksums()?;
+    Ok(archive)
+}
+
+// the header and trailer of a v1 archive checked, the messages are checked as they are decoded
+pub(crate) fn open_v1<'a>(buf: &'a [u8], opts: &UnpackOptions) -> Result<Decoder<'a>, Error> {
+    let header = read_header(buf, Format::V1, opts.legacy)?;
+    verify_trailer(buf, &header)?;
+    Ok(Decoder::with_limits(buf, opts.limits.clone()).with_header(&header))
+}
+
+/// Unpacks (or with opts.verify_only, checks) a v0 or v1 archive, whichever the header says it
+/// is. Legacy archives have nothing to go on so opts.legacy is rejected with BadMagic; use
+/// unpack_v0 or unpack_v1 for those
+pub fn unpack(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    if opts.legacy { return Err(Error::BadMagic); }
+    // mapped once, the unpacker goes on from the same mapping
+    let mmap = map_archive(infile, outdir, opts)?;
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    match Header::parse(buf)?.format {
+        Format::V0 => unpack_v0_buf(infile, buf, outdir, opts),
+        Format::V1 => unpack_v1_buf(buf, outdir, opts),
+    }
+}
+
+/// extract_v0 or extract_v1, whichever the header says buf is
+pub fn extract<'a>(buf: &'a [u8], path: &Path) -> Result<Cow<'a, [u8]>, Error> {
+    match Header::parse(buf)?.format {
+        Format::V0 => extract_v0(buf, path, false).map(Cow::Borrowed),
+        Format::V1 => extract_v1(buf, path, false),
+    }
+}
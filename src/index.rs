@@ -0,0 +1,193 @@
+use crate::common::{Error,ArchiveFormat1Tag};
+use crate::compress::Codec;
+use crate::header::{Header,TRAILER_LEN};
+use crate::list::EntryKind;
+
+const ENTRY_LEN: usize = 32;
+const FOOTER_LEN: usize = 16;
+
+/// Optional index of a v1 archive (FLAG_INDEX), written by pack_v1 right after the End tag and
+/// before the trailer, so lookups by path don't have to scan the messages
+/// <names> every full path back to back, no terminators
+/// <entries> one per file and dir, sorted by path bytes
+///   name_start: u64le, into names
+///   data_offset: u64le, of the file data from the start of the archive, 0 for dirs
+///   name_len: u32le
+///   data_len: u32le, 0 for dirs
+///   kind: u32le, the ArchiveFormat1Tag of the message (File, Dir or Compressed)
+///   reserved: u32le, zero
+/// Compressed entries cover the message from its codec byte to the end of the frame, so the codec
+/// and sizes are read from there
+/// names_len: u64le
+/// num_entries: u64le
+///
+/// The footer is found from the end of the archive. Readers that don't know the flag stop at End
+/// and never look at it
+pub struct Index<'a> {
+    buf: &'a [u8],
+    names: &'a [u8],
+    entries_start: usize,
+    count: usize,
+    body_start: usize,
+    // offset of the End tag, file data has to be before it
+    end: usize,
+    file_checksums: bool,
+    compression: bool,
+}
+
+/// One entry of an Index. offset and len (of the stored data) are checked to be inside the
+/// messages (with room for the checksum after the data if the archive has them), but the index
+/// isn't cross checked against the messages so a bad one can still point at the wrong bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry<'a> {
+    pub path: &'a [u8],
+    pub kind: EntryKind,
+    pub offset: u64,
+    pub len: u64,
+    /// of the file contents, only differs from len for compressed files
+    pub size: u64,
+    pub codec: Option<Codec>,
+}
+
+fn read_u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Index<'a> {
+    /// The index of buf if header says it has one. Only the footer and the End tag before the
+    /// index are checked here, entries are checked as they are read
+    pub fn parse(buf: &'a [u8], header: &Header) -> Result<Option<Index<'a>>, Error> {
+        if !header.index() { return Ok(None); }
+        let body_start = header.body_start();
+        let index_end = if header.trailer_checksum() { buf.len().checked_sub(TRAILER_LEN) } else { Some(buf.len()) };
+        let footer_start = index_end
+            .and_then(|end| end.checked_sub(FOOTER_LEN))
+            .filter(|&start| start > body_start)
+            .ok_or(Error::Truncated { offset: buf.len() })?;
+        let names_len = read_u64_at(buf, footer_start);
+        let count = read_u64_at(buf, footer_start + 8);
+        let bad = || Error::BadIndex { offset: footer_start };
+        let entries_len = count.checked_mul(ENTRY_LEN as u64).ok_or_else(bad)?;
+        let index_len = entries_len.checked_add(names_len).ok_or_else(bad)?;
+        // there has to be room for the End tag before the index
+        if index_len >= (footer_start - body_start) as u64 { return Err(bad()); }
+        let names_start = footer_start - index_len as usize;
+        let entries_start = names_start + names_len as usize;
+        let end = names_start - 1;
+        if buf[end] != ArchiveFormat1Tag::End as u8 { return Err(bad()); }
+        Ok(Some(Index {
+            buf,
+            names: &buf[names_start..entries_start],
+            entries_start,
+            count: count as usize,
+            body_start,
+            end,
+            file_checksums: header.file_checksums(),
+            compression: header.compression(),
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn entry(&self, i: usize) -> Result<IndexEntry<'a>, Error> {
+        let at = self.entries_start + i * ENTRY_LEN;
+        let bad = || Error::BadIndex { offset: at };
+        let name_start = read_u64_at(self.buf, at);
+        let offset = read_u64_at(self.buf, at + 8);
+        let name_len = read_u32_at(self.buf, at + 16) as u64;
+        let len = read_u32_at(self.buf, at + 20) as u64;
+        let tag = read_u32_at(self.buf, at + 24);
+        let compressed = tag == ArchiveFormat1Tag::Compressed as u32 && self.compression;
+        let kind = match tag {
+            x if x == ArchiveFormat1Tag::File as u32 || compressed => EntryKind::File,
+            x if x == ArchiveFormat1Tag::Dir as u32 => EntryKind::Dir,
+            _ => return Err(bad()),
+        };
+        let name_end = name_start.checked_add(name_len).ok_or_else(bad)?;
+        let path = self.names.get(name_start as usize..name_end as usize).ok_or_else(bad)?;
+        match kind {
+            EntryKind::File => {
+                let checksum = if self.file_checksums { 4 } else { 0 };
+                let data_end = offset.checked_add(len + checksum).ok_or_else(bad)?;
+                if offset < self.body_start as u64 || data_end > self.end as u64 { return Err(bad()); }
+            },
+            EntryKind::Dir => {
+                if offset != 0 || len != 0 { return Err(bad()); }
+            },
+        }
+        if compressed {
+            // <u8 codec> <u32le size> <u32le stored size>, which has to be the rest of the entry
+            if len < 9 { return Err(bad()); }
+            let start = offset as usize;
+            let codec = Codec::try_from(self.buf[start]).map_err(|_| bad())?;
+            let size = read_u32_at(self.buf, start + 1) as u64;
+            let stored = read_u32_at(self.buf, start + 5) as u64;
+            if stored != len - 9 { return Err(bad()); }
+            return Ok(IndexEntry { path, kind, offset: offset + 9, len: stored, size, codec: Some(codec) });
+        }
+        Ok(IndexEntry { path, kind, offset, len, size: len, codec: None })
+    }
+
+    /// Binary search for path, O(log n) entries are read
+    pub fn find(&self, path: &[u8]) -> Result<Option<IndexEntry<'a>>, Error> {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let entry = self.entry(mid)?;
+            match entry.path.cmp(path) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(entry)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every entry, in path order
+    pub fn entries(&self) -> impl Iterator<Item = Result<IndexEntry<'a>, Error>> + '_ {
+        (0..self.count).map(|i| self.entry(i))
+    }
+}
+
+/// Collects the entries while pack_v1 writes the messages and encodes the index at the end
+#[derive(Default)]
+pub(crate) struct IndexWriter {
+    names: Vec<u8>,
+    // (name_start, name_len, tag, data_offset, data_len)
+    entries: Vec<(usize, usize, u32, u64, u64)>,
+}
+
+impl IndexWriter {
+    pub(crate) fn add(&mut self, path: &[u8], tag: ArchiveFormat1Tag, offset: u64, len: u64) {
+        self.entries.push((self.names.len(), path.len(), tag as u32, offset, len));
+        self.names.extend_from_slice(path);
+    }
+
+    pub(crate) fn encode(mut self) -> Vec<u8> {
+        let names = &self.names;
+        self.entries.sort_unstable_by(|a, b| names[a.0..a.0 + a.1].cmp(&names[b.0..b.0 + b.1]));
+        let mut out = Vec::with_capacity(self.names.len() + self.entries.len() * ENTRY_LEN + FOOTER_LEN);
+        out.extend_from_slice(&self.names);
+        for &(name_start, name_len, tag, offset, len) in &self.entries {
+            out.extend_from_slice(&(name_start as u64).to_le_bytes());
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&(name_len as u32).to_le_bytes());
+            out.extend_from_slice(&(len as u32).to_le_bytes());
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.names.len() as u64).to_le_bytes());
+        out.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        out
+    }
+}
@@ -0,0 +1,73 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::path::Path;
+
+use crate::common::{Error,Stats,UnpackOptions};
+use crate::header::{map_archive_for_ring,open_v0};
+use crate::ioringv1::{RingUnpacker,ring_and_root};
+
+// (parent, last component), the last component is still zero terminated since it is the end of
+// the name
+fn split_name(name: &CStr) -> (Option<&[u8]>, &CStr) {
+    let bytes = name.to_bytes_with_nul();
+    match bytes.iter().rposition(|&b| b == b'/') {
+        None => (None, name),
+        Some(i) => (Some(&bytes[..i]), CStr::from_bytes_with_nul(&bytes[i + 1..]).unwrap()),
+    }
+}
+
+/// Same as unpack_v0 but through io_uring (see unpack_v1_ring). The dir table goes first as
+/// MkDirAt's of the last component in an O_PATH fd of the parent, which is opened on the ring
+/// too and kept until all of its subdirs are made, so siblings get created in parallel. Unlike
+/// unpack_v0 every dir's parent has to come before it in the dir table, which pack_v0 always
+/// does, or this fails with Error::Mkdirat. Then each file is an OpenAt (into a fixed file)
+/// linked with Writes straight from the mmap, with the path relative to the root like unpack_v0.
+/// opts.copy_file_range is ignored. See Confinement for how opts.confinement affects the calling
+/// process
+pub fn unpack_v0_ring(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive_for_ring(infile, outdir, opts)?;
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let archive = open_v0(buf, opts)?;
+    if opts.verify_only { return Ok(archive.stats()); }
+
+    // the index in the table of each dir's parent (None for the root) and how many subdirs each
+    // has, which is how many holds it gets
+    let dirs: Vec<&CStr> = archive.dirs().collect();
+    let mut index = HashMap::with_capacity(dirs.len());
+    let mut parents = Vec::with_capacity(dirs.len());
+    let mut subdirs = vec![0; dirs.len()];
+    for (i, name) in dirs.iter().enumerate() {
+        let parent = match split_name(name).0 {
+            None => None,
+            Some(parent) => Some(*index.get(parent).ok_or(Error::Mkdirat)?),
+        };
+        if let Some(parent) = parent { subdirs[parent] += 1; }
+        parents.push(parent);
+        index.insert(name.to_bytes(), i);
+    }
+
+    let (ring, root) = ring_and_root(opts, buf, outdir)?;
+    let mut ring = RingUnpacker::new(&root, ring)?;
+
+    // ring ids of the dirs with subdirs
+    let mut ids = vec![0; dirs.len()];
+    for (i, name) in dirs.iter().enumerate() {
+        let name = split_name(name).1;
+        root.check_mkdir(name)?;
+        let parent = parents[i].map_or(0, |parent| ids[parent]);
+        if let Some(id) = ring.dir(parent, name, subdirs[i])? {
+            ids[i] = id;
+        }
+        if parents[i].is_some() { ring.drop_hold(parent)?; }
+    }
+    // files are opened by their full path so every dir has to be there first
+    ring.finish()?;
+
+    for (name, data) in archive.files() {
+        ring.file(0, name, Cow::Borrowed(data))?;
+    }
+    ring.finish()?;
+    Ok(archive.stats())
+}
@@ -1,199 +1,603 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fs::File;
+use std::ops::Deref;
 use std::path::Path;
-use std::os::fd::{OwnedFd,AsRawFd};
+use std::os::fd::{OwnedFd,AsRawFd,FromRawFd,IntoRawFd,RawFd};
 use std::ffi::CStr;
-use std::rc::Rc;
 
-use memmap::MmapOptions;
 use io_uring::{opcode,types,IoUring};
 use io_uring::squeue::Flags;
 use io_uring::types::DestinationSlot;
 
-use crate::common::{Error,read_le_u32,ArchiveFormat1Tag};
-use crate::open::{chroot,openpath_at_cwd,mkdirat,openpathat};
-
-#[derive(Debug)]
-struct Entry<'a> {
-    dir_fd: Rc<OwnedFd>,
-    name: &'a CStr,
-    data: &'a [u8],
-}
+use crate::common::{Error,RingOptions,Stats,UnpackOptions,file_size,mmap_file};
+use crate::header::{map_archive_for_ring,open_v1};
+use crate::v1::{Message,verify_messages};
+use crate::open::{Confinement,Root};
 
 #[allow(dead_code)]
 #[derive(Debug)]
-enum RingError {
+pub(crate) enum RingError {
     Push,
     SubmitAndWait,
     DataTooBig,
     Open(i32),
     Write(i32),
+    Mkdir(i32),
+    Close(i32),
+    // waiting with nothing in flight would block forever
+    Stalled,
     Unk,
 }
 
-// runs every request in state until completion doing open, write+, (skips close)
-// assumes ring.submission().capacity() == 2 * state.len() so that we can put an open and write
-// right away
-const NUM_STATES: u64 = 2;
-fn run_state(state: &mut Vec<Entry>, ring: &mut IoUring) -> Result<(), RingError> {
-    assert!(ring.submission().is_empty());
-    for (i, entry) in state.iter().enumerate() {
-        let open = opcode::OpenAt::new(types::Fd(entry.dir_fd.as_raw_fd()), entry.name.as_ptr())
-            .flags((libc::O_WRONLY | libc::O_CREAT) as _)
-            .mode(0o755)
-            .file_index(Some(DestinationSlot::try_from_slot_target(i.try_into().unwrap()).unwrap()))
-            .build()
-            .flags(Flags::IO_LINK)
-            .user_data(NUM_STATES*(i as u64));
-        let len: u32 = entry.data.len().try_into().map_err(|_| RingError::DataTooBig)?; // todo could be shrunk
-        // can use this to force a resubmit of write
-        // let len = if len > 100 { len - 100 } else { len };
-        let write = opcode::Write::new(types::Fixed(i.try_into().unwrap()), entry.data.as_ptr(), len)
-            .offset(u64::MAX)  // == -1 = advance cursor of file
-            .build()
-            .user_data((NUM_STATES*(i as u64) + 1).try_into().unwrap());
-        unsafe {
-            ring.submission().push(&open).map_err(|_| RingError::Push)?;
-            ring.submission().push(&write).map_err(|_| RingError::Push)?;
+impl From<RingError> for Error {
+    fn from(e: RingError) -> Error {
+        match e {
+            RingError::Open(_) => Error::Open,
+            RingError::Write(_) => Error::Write,
+            RingError::Mkdir(_) => Error::Mkdirat,
+            _ => Error::Ring,
+        }
+    }
+}
+
+// what a cqe is for goes in the low bits of its user_data, the rest is a file slot or dir id
+const OPEN_FILE: u64 = 0;
+const WRITE: u64 = 1;
+// mkdir of an empty dir, the id is of the parent
+const MKDIR: u64 = 2;
+// mkdir linked to the open of the dir, the id is of the new dir
+const MKDIR_OPEN: u64 = 3;
+const OPEN_DIR: u64 = 4;
+const CLOSE: u64 = 5;
+const KIND_BITS: u32 = 3;
+
+fn user_data(kind: u64, i: usize) -> u64 {
+    ((i as u64) << KIND_BITS) | kind
+}
+
+// Work waiting to go on the ring. File and Dir need the fd of their dir, so they wait in
+// DirState::waiting until its open completes
+#[derive(Debug)]
+enum Op<'a> {
+    // open+write linked, the open goes into fixed file slot
+    File { dir: usize, name: &'a CStr, slot: usize },
+    // the rest of a short write
+    Write { slot: usize },
+    // mkdir, linked to an O_PATH open of it into dirs[open] unless the dir is empty
+    Dir { dir: usize, name: &'a CStr, open: Option<usize> },
+    Close { fd: RawFd },
+}
+
+impl Op<'_> {
+    fn sqes(&self) -> usize {
+        match self {
+            Op::File { .. } | Op::Dir { open: Some(_), .. } => 2,
+            _ => 1,
         }
     }
+}
+
+// A dir that things get created in. users counts the queued ops that still need fd, plus the
+// holds the caller asked for in RingUnpacker::dir (one until the Pop for v1), and once it drops
+// to zero the fd is closed through the ring. This is the refcount the Rc used to be, except the
+// last user can be an sqe that hasn't completed yet
+#[derive(Debug, Default)]
+struct DirState<'a> {
+    fd: Option<OwnedFd>,
+    parent: usize,
+    waiting: Vec<Op<'a>>,
+    users: usize,
+}
+
+// A file on a fixed slot, data[written..] is left to write. Decompressed data is owned here, so
+// it stays put until the last write of it completes
+#[derive(Debug, Default, Clone)]
+struct FileSlot<'a> {
+    dir: usize,
+    data: Cow<'a, [u8]>,
+    written: usize,
+}
+
+impl FileSlot<'_> {
+    fn left(&self) -> &[u8] {
+        &self.data[self.written..]
+    }
+}
+
+/// Queue depth of the ring unpackers when RingOptions::depth is 0
+pub const DEFAULT_RING_DEPTH: usize = 256;
+// the sq is twice the depth and can have at most 32768 entries
+const MAX_RING_DEPTH: usize = 16384;
+// how long the sqpoll thread spins before it sleeps and submit() has to wake it
+const SQPOLL_IDLE_MS: u32 = 1000;
+// a registered buffer can be at most 1GiB, so bigger archives are registered in chunks of that
+const FIXED_BUFFER_MAX: usize = 1 << 30;
+
+fn build_ring(opts: &RingOptions, entries: u32) -> std::io::Result<IoUring> {
+    let mut builder = IoUring::builder();
+    if opts.sqpoll { builder.setup_sqpoll(SQPOLL_IDLE_MS); }
+    if opts.single_issuer { builder.setup_single_issuer(); }
+    if opts.defer_taskrun { builder.setup_defer_taskrun(); }
+    if opts.coop_taskrun { builder.setup_coop_taskrun(); }
+    builder.build(entries)
+}
+
+fn register_buffers(ring: &IoUring, buf: &[u8]) -> std::io::Result<()> {
+    let iovecs: Vec<libc::iovec> = buf.chunks(FIXED_BUFFER_MAX)
+        .map(|chunk| libc::iovec { iov_base: chunk.as_ptr() as *mut _, iov_len: chunk.len() })
+        .collect();
+    // buf outlives the ring in both callers, and the kernel only reads from it
+    unsafe { ring.submitter().register_buffers(&iovecs) }
+}
+
+/// Which RingOptions setup flags the running kernel takes, each on its own (defer_taskrun along
+/// with single_issuer since it needs it), and whether it takes the combination that was asked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RingSupport {
+    pub sqpoll: bool,
+    pub single_issuer: bool,
+    pub defer_taskrun: bool,
+    pub coop_taskrun: bool,
+    /// registering a private mapping as a fixed buffer works
+    pub fixed_buffers: bool,
+    pub requested: bool,
+}
 
-    let n = ring.submission().len();
-    ring.submit_and_wait(n).map_err(|_| RingError::SubmitAndWait)?;
-    let mut remaining: i32 = state.len().try_into().unwrap();
-    loop {
-        let mut submission = unsafe { ring.submission_shared() };
-        let completion = unsafe { ring.completion_shared() };
-        assert!(submission.is_empty());
-        for cqe in completion {
-            let i = cqe.user_data() / NUM_STATES;
-            match cqe.user_data() % NUM_STATES {
-                0 => {  // this is the open
-                    if cqe.result() < 0 { return Err(RingError::Open(cqe.result())); }
+// a ring with opts, with a fixed buffer registered if it asks for them
+fn probe(opts: &RingOptions) -> bool {
+    let Ok(ring) = build_ring(opts, 8) else { return false };
+    if !opts.fixed_buffers { return true; }
+    // anonymous mappings are private and writable, like the map_copy of the archive
+    let Ok(buf) = memmap::MmapMut::map_anon(4096) else { return false };
+    register_buffers(&ring, &buf).is_ok()
+}
+
+/// Sets up small rings to find out which of the options the kernel takes, see RingSupport
+pub fn ring_support(opts: &RingOptions) -> RingSupport {
+    RingSupport {
+        sqpoll: probe(&RingOptions { sqpoll: true, ..Default::default() }),
+        single_issuer: probe(&RingOptions { single_issuer: true, ..Default::default() }),
+        defer_taskrun: probe(&RingOptions { single_issuer: true, defer_taskrun: true, ..Default::default() }),
+        coop_taskrun: probe(&RingOptions { coop_taskrun: true, ..Default::default() }),
+        fixed_buffers: probe(&RingOptions { fixed_buffers: true, ..Default::default() }),
+        requested: probe(opts),
+    }
+}
+
+/// The archive as the ring unpackers map it, see RingOptions::fixed_buffers
+pub(crate) enum RingMmap {
+    Shared(memmap::Mmap),
+    // registering a buffer pins it for writing, which a read only mapping can't be
+    Private(memmap::MmapMut),
+}
+
+impl Deref for RingMmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            RingMmap::Shared(mmap) => mmap,
+            RingMmap::Private(mmap) => mmap,
+        }
+    }
+}
+
+// like mmap_file, None for an empty file
+pub(crate) fn mmap_for_ring(file: &File, opts: &RingOptions) -> Result<Option<RingMmap>, Error> {
+    if !opts.fixed_buffers { return Ok(mmap_file(file)?.map(RingMmap::Shared)); }
+    if file_size(file)? == 0 { return Ok(None); }
+    let mmap = unsafe { memmap::MmapOptions::new().map_copy(file) }.map_err(|_| Error::Mmap)?;
+    Ok(Some(RingMmap::Private(mmap)))
+}
+
+// The ring as opts asks, with the files and buffers registered
+pub(crate) struct RingSetup<'a> {
+    ring: IoUring,
+    fixed: Option<&'a [u8]>,
+    depth: usize,
+}
+
+impl<'a> RingSetup<'a> {
+    /// with opts.fixed_buffers, buf is registered and all file data has to come from it
+    pub(crate) fn new(opts: &RingOptions, buf: &'a [u8]) -> Result<RingSetup<'a>, Error> {
+        let depth = if opts.depth == 0 { DEFAULT_RING_DEPTH } else { opts.depth.min(MAX_RING_DEPTH) };
+        // every outstanding op takes at most 2 sqes, so everything fits
+        let ring = build_ring(opts, (2 * depth).try_into().unwrap()).map_err(|_| Error::Ring)?;
+        // one slot per outstanding file
+        ring.submitter().register_files_sparse(depth.try_into().unwrap()).map_err(|_| Error::Ring)?;
+        // registering nothing is EINVAL, and an empty archive has nothing to write anyway
+        let fixed = if opts.fixed_buffers && !buf.is_empty() {
+            register_buffers(&ring, buf).map_err(|_| Error::Ring)?;
+            Some(buf)
+        } else {
+            None
+        };
+        Ok(RingSetup { ring, fixed, depth })
+    }
+}
+
+// With Confinement::Chroot the ring is set up before Root::new moves us into a user namespace,
+// where even root is held to RLIMIT_MEMLOCK for the pinned buffers. Except with sqpoll, since its
+// kernel thread means we are no longer single threaded and the unshare fails
+pub(crate) fn ring_and_root<'a>(opts: &UnpackOptions, buf: &'a [u8], outdir: &Path) -> Result<(RingSetup<'a>, Root), Error> {
+    if opts.ring.sqpoll {
+        let root = Root::new(outdir, opts.confinement)?;
+        Ok((RingSetup::new(&opts.ring, buf)?, root))
+    } else {
+        let ring = RingSetup::new(&opts.ring, buf)?;
+        Ok((ring, Root::new(outdir, opts.confinement)?))
+    }
+}
+
+// Everything open+write, mkdir+open and close goes through here. There are no batches: ops are
+// queued as the parser finds them and go into the sq as soon as their dir is open, the sq is
+// submitted whenever it is a quarter full, and completions are reaped whenever the parser comes
+// back (which doesn't take a syscall). The parser only blocks once depth ops are outstanding.
+// A file's fixed slot goes back on the free list as soon as its last write completes, and
+// opening into a used slot closes what was there, so files never need a close. unpack_v0_ring
+// drives it too
+pub(crate) struct RingUnpacker<'a> {
+    ring: IoUring,
+    // the registered buffers, all data written with WriteFixed comes from here
+    fixed: Option<&'a [u8]>,
+    confinement: Confinement,
+    // the kernel copies these when the sqe is submitted, so they just have to outlive the ring
+    file_how: types::OpenHow,
+    dir_how: types::OpenHow,
+    // by id, 0 is the root which is never released. Ids are reused once a dir is closed
+    dirs: Vec<DirState<'a>>,
+    free_dirs: Vec<usize>,
+    ready: VecDeque<Op<'a>>,
+    files: Vec<FileSlot<'a>>,
+    free_slots: Vec<usize>,
+    // ops queued (including closes) that haven't completed, at most depth
+    outstanding: usize,
+    // sqes pushed that haven't completed
+    in_flight: usize,
+    depth: usize,
+}
+
+impl<'a> RingUnpacker<'a> {
+    /// ring comes from RingSetup::new
+    pub(crate) fn new(root: &Root, ring: RingSetup<'a>) -> Result<RingUnpacker<'a>, Error> {
+        let RingSetup { ring, fixed, depth } = ring;
+        let root_fd = root.fd().try_clone().map_err(|_| Error::Open)?;
+        Ok(RingUnpacker {
+            ring,
+            fixed,
+            confinement: root.confinement(),
+            file_how: types::OpenHow::new()
+                .flags((libc::O_WRONLY | libc::O_CREAT) as _)
+                .mode(0o755)
+                .resolve(libc::RESOLVE_IN_ROOT),
+            dir_how: types::OpenHow::new()
+                .flags((libc::O_DIRECTORY | libc::O_PATH | libc::O_CLOEXEC) as _)
+                .resolve(libc::RESOLVE_IN_ROOT),
+            dirs: vec![DirState { fd: Some(root_fd), parent: 0, waiting: vec![], users: 1 }],
+            free_dirs: vec![],
+            ready: VecDeque::new(),
+            files: vec![FileSlot::default(); depth],
+            // popped from the end, so slots get used from 0 up
+            free_slots: (0..depth).rev().collect(),
+            outstanding: 0,
+            in_flight: 0,
+            depth,
+        })
+    }
+
+    fn queue(&mut self, dir: usize, op: Op<'a>) -> Result<(), RingError> {
+        let state = &mut self.dirs[dir];
+        state.users += 1;
+        if state.fd.is_some() {
+            self.ready.push_back(op);
+        } else {
+            state.waiting.push(op);
+        }
+        self.outstanding += 1;
+        self.pump()
+    }
+
+    /// creates name (which can be a path) in dir and writes data to it. dir 0 is the root. Owned
+    /// data is held until it has been written
+    pub(crate) fn file(&mut self, dir: usize, name: &'a CStr, data: Cow<'a, [u8]>) -> Result<(), RingError> {
+        let _: u32 = data.len().try_into().map_err(|_| RingError::DataTooBig)?; // todo could be shrunk
+        // pump keeps outstanding below depth so there is always a slot, but don't count on it
+        while self.free_slots.is_empty() {
+            self.wait()?;
+        }
+        let slot = self.free_slots.pop().unwrap();
+        self.files[slot] = FileSlot { dir, data, written: 0 };
+        self.queue(dir, Op::File { dir, name, slot })
+    }
+
+    /// mkdirs name in dir. With holds it is also opened and the id returned, to create things in
+    /// it; it stays open until drop_hold has been called holds times and everything in it is done
+    pub(crate) fn dir(&mut self, dir: usize, name: &'a CStr, holds: usize) -> Result<Option<usize>, RingError> {
+        let open = if holds == 0 {
+            None
+        } else {
+            let state = DirState { fd: None, parent: dir, waiting: vec![], users: holds };
+            let id = match self.free_dirs.pop() {
+                Some(id) => { self.dirs[id] = state; id },
+                None => { self.dirs.push(state); self.dirs.len() - 1 },
+            };
+            Some(id)
+        };
+        self.queue(dir, Op::Dir { dir, name, open })?;
+        Ok(open)
+    }
+
+    // one user of dir is done with it
+    fn release(&mut self, dir: usize) {
+        self.dirs[dir].users -= 1;
+        self.close_if_unused(dir);
+    }
+
+    // a dir can lose its last hold with nothing left in it before its own open completes, then
+    // this happens at the open instead
+    fn close_if_unused(&mut self, dir: usize) {
+        let state = &mut self.dirs[dir];
+        if state.users != 0 { return; }
+        let Some(fd) = state.fd.take() else { return };
+        self.ready.push_back(Op::Close { fd: fd.into_raw_fd() });
+        self.outstanding += 1;
+        self.free_dirs.push(dir);
+    }
+
+    /// the caller won't queue anything more in dir for one of its holds
+    pub(crate) fn drop_hold(&mut self, dir: usize) -> Result<(), RingError> {
+        self.release(dir);
+        self.pump()
+    }
+
+    fn write_sqe(&self, slot: usize) -> io_uring::squeue::Entry {
+        let data = self.files[slot].left();
+        let fd = types::Fixed(slot.try_into().unwrap());
+        // checked to fit when the file was queued
+        let len = data.len() as u32;
+        // offset -1 advances the cursor of the file
+        let write = match self.fixed.and_then(|buf| fixed_buffer(buf, data)) {
+            Some((index, fixed_len)) => opcode::WriteFixed::new(fd, data.as_ptr(), fixed_len, index)
+                .offset(u64::MAX)
+                .build(),
+            None => opcode::Write::new(fd, data.as_ptr(), len).offset(u64::MAX).build(),
+        };
+        write.user_data(user_data(WRITE, slot))
+    }
+
+    fn push(&mut self, op: &Op<'a>) -> Result<(), RingError> {
+        // built first since the sq borrows the ring
+        let write = match *op {
+            Op::File { slot, .. } | Op::Write { slot } => Some(self.write_sqe(slot)),
+            _ => None,
+        };
+        let mut submission = self.ring.submission();
+        match *op {
+            Op::File { dir, name, slot } => {
+                let dir_fd = types::Fd(self.dirs[dir].fd.as_ref().unwrap().as_raw_fd());
+                let target = DestinationSlot::try_from_slot_target(slot.try_into().unwrap()).unwrap();
+                let open = match self.confinement {
+                    Confinement::Chroot => opcode::OpenAt::new(dir_fd, name.as_ptr())
+                        .flags((libc::O_WRONLY | libc::O_CREAT) as _)
+                        .mode(0o755)
+                        .file_index(Some(target))
+                        .build(),
+                    Confinement::Openat2 => opcode::OpenAt2::new(dir_fd, name.as_ptr(), &self.file_how)
+                        .file_index(Some(target))
+                        .build(),
+                };
+                let open = open.flags(Flags::IO_LINK).user_data(user_data(OPEN_FILE, slot));
+                unsafe {
+                    submission.push(&open).map_err(|_| RingError::Push)?;
+                    submission.push(write.as_ref().unwrap()).map_err(|_| RingError::Push)?;
                 }
-                1 => {  // this is a write
-                    if cqe.result() < 0 { return Err(RingError::Write(cqe.result())); }
-                    let written = cqe.result() as usize; // known positive
-                    let entry: &mut Entry = &mut state[i as usize];
-                    if written == entry.data.len() { // all done
-                        remaining -= 1;
-                        // this maybe not even necessary since choosing the same file index should just close it
-                        // let close = opcode::Close::new(types::Fixed(i.try_into().unwrap()));
-                    } else { // needs resubmission
-                        // println!("resubmitting data of size {} written {}", entry.data.len(), written);
-                        entry.data = &entry.data[written..];
-                        let len = entry.data.len().try_into().map_err(|_| RingError::DataTooBig)?; // todo could be shrunk
-                        let write = opcode::Write::new(types::Fixed(i.try_into().unwrap()), entry.data.as_ptr(), len)
-                            .offset(u64::MAX)  // == -1 = advance cursor of file
-                            .build()
-                            .user_data((NUM_STATES*i + 1).try_into().unwrap());
+            },
+            Op::Write { .. } => unsafe {
+                submission.push(write.as_ref().unwrap()).map_err(|_| RingError::Push)?;
+            },
+            Op::Dir { dir, name, open } => {
+                let dir_fd = types::Fd(self.dirs[dir].fd.as_ref().unwrap().as_raw_fd());
+                let mkdir = opcode::MkDirAt::new(dir_fd, name.as_ptr()).mode(0o755).build();
+                match open {
+                    None => unsafe {
+                        submission.push(&mkdir.user_data(user_data(MKDIR, dir))).map_err(|_| RingError::Push)?;
+                    },
+                    Some(id) => {
+                        // linked so the open only runs once the mkdir succeeded
+                        let mkdir = mkdir.flags(Flags::IO_LINK).user_data(user_data(MKDIR_OPEN, id));
+                        let open = match self.confinement {
+                            Confinement::Chroot => opcode::OpenAt::new(dir_fd, name.as_ptr())
+                                .flags(libc::O_DIRECTORY | libc::O_PATH | libc::O_CLOEXEC)
+                                .build(),
+                            Confinement::Openat2 => opcode::OpenAt2::new(dir_fd, name.as_ptr(), &self.dir_how).build(),
+                        };
+                        let open = open.user_data(user_data(OPEN_DIR, id));
                         unsafe {
-                            submission.push(&write).map_err(|_| RingError::Push)?;
+                            submission.push(&mkdir).map_err(|_| RingError::Push)?;
+                            submission.push(&open).map_err(|_| RingError::Push)?;
                         }
-                    }
+                    },
                 }
-                _ => { // if we wanted a close, this would be it
-                    // this maybe not even necessary since choosing the same file index should just
-                    // cause it to be closed ...
-                    // if you do do close, then use Close::new(Fixed(i))
-                    return Err(RingError::Unk);
+            },
+            Op::Close { fd } => {
+                let close = opcode::Close::new(types::Fd(fd)).build().user_data(user_data(CLOSE, 0));
+                unsafe { submission.push(&close).map_err(|_| RingError::Push)?; }
+            },
+        }
+        self.in_flight += op.sqes();
+        Ok(())
+    }
+
+    // moves as many ready ops into the sq as fit
+    fn push_ready(&mut self) -> Result<(), RingError> {
+        while let Some(op) = self.ready.front() {
+            let free = {
+                let submission = self.ring.submission();
+                submission.capacity() - submission.len()
+            };
+            if op.sqes() > free { break; }
+            let op = self.ready.pop_front().unwrap();
+            self.push(&op)?;
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32) -> Result<(), RingError> {
+        let i = (user_data >> KIND_BITS) as usize;
+        match user_data & ((1 << KIND_BITS) - 1) {
+            OPEN_FILE => {
+                if result < 0 { return Err(RingError::Open(result)); }
+                self.release(self.files[i].dir);
+            },
+            WRITE => {
+                if result < 0 { return Err(RingError::Write(result)); }
+                let written = result as usize; // known positive
+                let file = &mut self.files[i];
+                if written == 0 && !file.left().is_empty() { return Err(RingError::Write(result)); }
+                file.written += written;
+                if file.left().is_empty() {
+                    // drops owned data, nothing reads it anymore
+                    *file = FileSlot::default();
+                    self.free_slots.push(i);
+                    self.outstanding -= 1;
+                } else {
+                    // needs resubmission, which just goes on the end of the queue
+                    self.ready.push_back(Op::Write { slot: i });
                 }
-            }
+            },
+            MKDIR => {
+                if result < 0 { return Err(RingError::Mkdir(result)); }
+                self.outstanding -= 1;
+                self.release(i);
+            },
+            MKDIR_OPEN => {
+                if result < 0 { return Err(RingError::Mkdir(result)); }
+            },
+            OPEN_DIR => {
+                if result < 0 { return Err(RingError::Open(result)); }
+                let state = &mut self.dirs[i];
+                state.fd = Some(unsafe { OwnedFd::from_raw_fd(result) });
+                self.ready.extend(state.waiting.drain(..));
+                let parent = state.parent;
+                self.outstanding -= 1;
+                self.close_if_unused(i);
+                self.release(parent);
+            },
+            CLOSE => {
+                if result < 0 { return Err(RingError::Close(result)); }
+                self.outstanding -= 1;
+            },
+            _ => return Err(RingError::Unk),
+        }
+        Ok(())
+    }
+
+    // handles whatever completions are there, without a syscall
+    fn reap(&mut self) -> Result<(), RingError> {
+        loop {
+            let Some(cqe) = self.ring.completion().next() else { break };
+            self.in_flight -= 1;
+            self.complete(cqe.user_data(), cqe.result())?;
         }
+        Ok(())
+    }
 
-        submission.sync();
-        let n = submission.len();
-        if n == 0 { break; }
-        ring.submit_and_wait(n).map_err(|_| RingError::SubmitAndWait)?;
+    // called after every op the parser queues, only blocks if depth ops are outstanding
+    fn pump(&mut self) -> Result<(), RingError> {
+        self.reap()?;
+        self.push_ready()?;
+        let quarter_full = {
+            let submission = self.ring.submission();
+            submission.len() >= submission.capacity() / 4
+        };
+        if quarter_full {
+            self.ring.submit().map_err(|_| RingError::SubmitAndWait)?;
+        }
+        while self.outstanding >= self.depth {
+            self.wait()?;
+        }
+        Ok(())
     }
-    assert!(remaining == 0);
-    state.clear();
-    Ok(())
+
+    // submits everything and blocks until at least one completion
+    fn wait(&mut self) -> Result<(), RingError> {
+        self.push_ready()?;
+        // anything outstanding is in flight, ready (and then the sq is full) or waiting on the
+        // open of a dir that is one of those, so this is a bug but one that shouldn't hang
+        if self.in_flight == 0 { return Err(RingError::Stalled); }
+        self.ring.submit_and_wait(1).map_err(|_| RingError::SubmitAndWait)?;
+        self.reap()?;
+        self.push_ready()
+    }
+
+    /// waits for everything queued to complete
+    pub(crate) fn finish(&mut self) -> Result<(), RingError> {
+        while self.outstanding > 0 {
+            self.wait()?;
+        }
+        Ok(())
+    }
+}
+
+// (buffer index, length) to write data as a WriteFixed from the buffers buf was registered as.
+// A write can't cross into the next buffer so the length stops at its end, and the rest goes as
+// a short write. None for empty data or data that isn't in buf
+fn fixed_buffer(buf: &[u8], data: &[u8]) -> Option<(u16, u32)> {
+    if data.is_empty() { return None; }
+    let offset = (data.as_ptr() as usize).checked_sub(buf.as_ptr() as usize)?;
+    if offset + data.len() > buf.len() { return None; }
+    let index = offset / FIXED_BUFFER_MAX;
+    let len = data.len().min((index + 1) * FIXED_BUFFER_MAX - offset);
+    Some((index.try_into().ok()?, len as u32))
 }
 
 // there are loads of ways to use io_uring for our task
 // one big decision point is whether to use regular or direct fd's
 //   - note that openat always takes a regular fd for the directory fd arg so we can only ever
 //     openat a dir into a regular fd anyways
-//   - that doesn't mean we can't use uring for the opendir, but it presents a big challenge since
-//     we need the result of that open for subsequent opens, so you have to submit anyways
-// so a simple approach is to only use it for writes to files, since those take an open,write+,close
-// making a dir does mkdirat,openat,close but we have to have those fd's avaiable for all the
-// openats, so the ordering isn't so easy
-// plus for the linux example, there are 5139 dirs and 79455 files so start with the bigger thing
-#[allow(unused_variables)]
-pub fn unpack_v1_ring(args: &[String]) {
-    let inname = args.get(0).ok_or(Error::NoOutfile).unwrap();
-    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
-
-    let inpath = Path::new(&inname);
-    let outpath = Path::new(&outname);
-    assert!(inpath.is_file(), "{:?} should be a file", inpath);
-    assert!(outpath.is_dir(), "{:?} should be a dir", outpath);
-
-    let infile = File::open(inpath).unwrap();
-    let mmap = unsafe { MmapOptions::new().map(&infile).unwrap() };
-
-    chroot(&outpath);
-
-    let mut stack: Vec<Rc<OwnedFd>> = Vec::with_capacity(32);  // always non-empty
-    stack.push(openpath_at_cwd(c".").unwrap().into());
-
-    let batch_size: usize = 256;
-    let mut ring = IoUring::new((2 * batch_size).try_into().unwrap()).unwrap();
-    let mut state: Vec<Entry> = Vec::with_capacity(batch_size);
-
-    // I don't think there's a difference for us for this
-    ring.submitter().register_files_sparse(batch_size.try_into().unwrap()).unwrap();
-    // let fds: [i32; 256] = [-1; 256];
-    // ring.submitter().register_files(&fds).unwrap();
-
-    let mut cur = &mmap[..];
-    loop {
-        match cur.get(0).map(|x| x.try_into()) {
-            Some(Ok(ArchiveFormat1Tag::File)) => {
-                cur = &cur[1..];
-                let parent = stack.last().unwrap();
-                let name = unsafe { CStr::from_bytes_with_nul_unchecked(cur) };
-                let zbi = cur.iter().position(|&x| x == 0).unwrap(); // todo do better
-                cur = &cur[zbi+1..];
-                let len = read_le_u32(&mut cur) as usize;
-                let data = &cur[..len];
-                state.push(Entry { dir_fd: parent.clone(), name: name, data: data });
-                if state.len() == batch_size {
-                    run_state(&mut state, &mut ring).unwrap();
-                }
-                cur = &cur[len..];
-            },
-            Some(Ok(ArchiveFormat1Tag::Dir)) => {
-                cur = &cur[1..];
-                let parent = stack.last().unwrap();
-                let name = unsafe { CStr::from_bytes_with_nul_unchecked(cur) };
-                mkdirat(parent, name).unwrap();
-                let zbi = cur.iter().position(|&x| x == 0).unwrap(); // todo do better
-                cur = &cur[zbi+1..];
-                if *cur.get(0).unwrap() == (ArchiveFormat1Tag::Pop as u8) {
-                    // fast path for empty dir, never open the dir and push it
-                    cur = &cur[1..];
-                } else {
-                    let fd = openpathat(parent, name).unwrap();
-                    stack.push(fd.into());
+//   - so the dir opens go through the ring too but into regular fds, and anything in a dir waits
+//     until the cqe of its open tells us the fd
+// files take open,write+ (the close is implicit in reusing the fixed slot) and dirs take
+// mkdir,open,close. Both pairs are linked, the closes go once nothing queued needs the dir
+// for the linux example, there are 5139 dirs and 79455 files
+/// Same as unpack_v1 but every open, write, mkdir and close goes through io_uring, set up as
+/// opts.ring asks. Compressed files are decompressed into memory first, and held until their
+/// write completes. See Confinement for how opts.confinement affects the calling process
+pub fn unpack_v1_ring(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive_for_ring(infile, outdir, opts)?;
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let decoder = open_v1(buf, opts)?;
+    if opts.verify_only { return verify_messages(decoder); }
+
+    let (ring, root) = ring_and_root(opts, buf, outdir)?;
+
+    let mut stats = Stats::default();
+    // ids of the dirs we are in, always non-empty
+    let mut stack: Vec<usize> = Vec::with_capacity(32);
+    stack.push(0);
+
+    let mut ring = RingUnpacker::new(&root, ring)?;
+
+    for message in decoder {
+        // the decoder guarantees Pop's are balanced, so there is always a parent
+        let parent = *stack.last().unwrap();
+        let message = message?;
+        message.count(&mut stats);
+        match message {
+            Message::File { name, data } => ring.file(parent, name, Cow::Borrowed(data))?,
+            // decompressed whole, then written like any other file
+            Message::Compressed { name, file } => ring.file(parent, name, Cow::Owned(file.to_vec()?))?,
+            Message::Dir { name, empty } => {
+                root.check_mkdir(name)?;
+                // fast path for empty dir, never open the dir and push it
+                if let Some(id) = ring.dir(parent, name, if empty { 0 } else { 1 })? {
+                    stack.push(id);
                 }
             },
-            Some(Ok(ArchiveFormat1Tag::Pop)) => {
-                cur = &cur[1..];
-                // always expected to be nonempty, todo handle gracefully for malicious archives
-                stack.pop().unwrap();
-                // TODO this calls close(2) directly (once the rc count is dropped) and doesn't
-                // actully use io_uring ...
-            },
-            Some(Err(_)) => {
-                let b = cur[0];
-                panic!("oh no got bad tag byte {b}");
-            },
-            None => {
-                run_state(&mut state, &mut ring).unwrap();
-                break;
-            }
+            Message::Pop => ring.drop_hold(stack.pop().unwrap())?,
         }
     }
+    ring.finish()?;
+    Ok(stats)
 }
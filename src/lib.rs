@@ -0,0 +1,34 @@
+pub mod common;
+pub mod open;
+pub mod liblistdir;
+pub mod ioringv1;
+pub mod ioringv0;
+pub mod parallel;
+pub mod v0;
+pub mod v1;
+pub mod v1stream;
+pub mod sandbox;
+pub mod list;
+pub mod header;
+pub mod checksum;
+pub mod index;
+pub mod compress;
+pub mod convert;
+pub mod tarball;
+pub mod cpio;
+
+pub use common::{Error,Format,Limit,PackOptions,RingOptions,Stats,UnpackLimits,UnpackOptions};
+pub use compress::Codec;
+pub use convert::{Converted,OnSkipped,Skipped,SkippedKind};
+pub use tarball::{from_tar,to_tar};
+pub use cpio::{from_cpio,pack_cpio};
+pub use open::Confinement;
+pub use v0::{extract_v0,pack_v0,pack_v0_dir,unpack_v0};
+pub use v1::{extract_v1,pack_v1,unpack_v1};
+pub use v1stream::unpack_v1_stream;
+pub use ioringv1::{RingSupport,ring_support,unpack_v1_ring};
+pub use ioringv0::unpack_v0_ring;
+pub use parallel::{unpack_v0_parallel,unpack_v1_parallel};
+pub use sandbox::sandboxed;
+pub use list::{Entries,Entry,EntryKind};
+pub use header::{Header,extract,unpack};
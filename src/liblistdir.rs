@@ -4,7 +4,8 @@ use rustix::fs::{RawDir,FileType};
 use std::os::fd::OwnedFd;
 use std::fs::File;
 
-const MAX_DIR_DEPTH: usize = 32;
+/// Deepest nesting list_dir descends into, the dir it is given is depth 0
+pub const MAX_DIR_DEPTH: usize = 32;
 
 use crate::common::Error;
 use crate::open::{openat,opendirat,opendir};
@@ -37,14 +38,26 @@ use crate::open::{openat,opendirat,opendir};
 // }
 // ----------------
 
+/// What to do after on_dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    /// descend into the dir, leave_dir is called once it is done
+    Continue,
+    /// don't descend into the dir and don't call leave_dir
+    Skip,
+}
+
+/// Callbacks for list_dir. Any error returned stops the walk and is returned from list_dir; errors
+/// from the walk itself (open, getdents, ...) are converted with From
 pub trait Visitor {
-    fn on_file(&mut self, name: &CStr, file: File) -> ();
-    fn on_dir(&mut self, name: &CStr) -> ();
-    fn leave_dir(&mut self) -> ();
+    type Error: From<Error>;
+    fn on_file(&mut self, name: &CStr, file: File) -> Result<(), Self::Error>;
+    fn on_dir(&mut self, name: &CStr) -> Result<Walk, Self::Error>;
+    fn leave_dir(&mut self) -> Result<(), Self::Error>;
 }
 
-fn list_dir2_rec<V: Visitor>(curdir: &OwnedFd, v: &mut V, depth: usize) -> Result<(), Error> {
-    if depth > MAX_DIR_DEPTH { return Err(Error::DirTooDeep); }
+fn list_dir2_rec<V: Visitor>(curdir: &OwnedFd, v: &mut V, depth: usize) -> Result<(), V::Error> {
+    if depth > MAX_DIR_DEPTH { return Err(Error::DirTooDeep.into()); }
 
     let mut buf = Vec::with_capacity(4096);
     let mut iter = RawDir::new(&curdir, buf.spare_capacity_mut());
@@ -56,18 +69,20 @@ fn list_dir2_rec<V: Visitor>(curdir: &OwnedFd, v: &mut V, depth: usize) -> Resul
             FileType::RegularFile => {
                 // let name = unsafe { OsStr::from_encoded_bytes_unchecked(entry.file_name().to_bytes()) };
                 let name = entry.file_name();
-                let _ = v.on_file(name, openat(curdir, name)?);
+                v.on_file(name, openat(curdir, name)?)?;
             },
             FileType::Directory => {
                 if entry.file_name() == c"." || entry.file_name() == c".." {
                     continue;
                 }
-                let newdirfd = opendirat(curdir, entry.file_name())?;
                 let curname = entry.file_name();
 
-                v.on_dir(curname);
+                if v.on_dir(curname)? == Walk::Skip {
+                    continue;
+                }
+                let newdirfd = opendirat(curdir, curname)?;
                 list_dir2_rec(&newdirfd, v, depth + 1)?;
-                v.leave_dir();
+                v.leave_dir()?;
             },
             _ => {}
         }
@@ -76,7 +91,7 @@ fn list_dir2_rec<V: Visitor>(curdir: &OwnedFd, v: &mut V, depth: usize) -> Resul
     Ok(())
 }
 
-pub fn list_dir<V: Visitor>(dir: &Path, v: &mut V) -> Result<(), Error> {
+pub fn list_dir<V: Visitor>(dir: &Path, v: &mut V) -> Result<(), V::Error> {
     let dirfd = opendir(dir)?;
     list_dir2_rec(&dirfd, v, 0)?;
     Ok(())
@@ -0,0 +1,135 @@
+use std::ffi::{CStr,OsStr};
+use std::os::unix::prelude::OsStrExt;
+use std::path::PathBuf;
+
+use crate::common::{Error,Format,Stats};
+use crate::header::{Header,verify_trailer};
+use crate::index::Index;
+use crate::v0::Archive;
+use crate::v1::{Decoder,Message,Paths};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+/// One file or dir in an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// relative to the root of the archive
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// bytes of file contents, 0 for dirs
+    pub size: u64,
+}
+
+enum Inner<'a> {
+    // v0 is validated up front so listing it can't fail
+    V0(Box<dyn Iterator<Item = (&'a CStr, EntryKind, u64)> + 'a>),
+    V1 { decoder: Decoder<'a>, paths: Paths },
+    // v1 with an index, next is the next entry
+    Index { index: Index<'a>, next: usize },
+}
+
+/// Iterator over the entries of a v0 or v1 archive without unpacking it. v0 lists all the dirs
+/// then all the files, v1 lists in archive order, or in path order straight from the index when
+/// it has one. stats() has the totals of everything listed so far, counted the same way as the
+/// pack and unpack functions do
+pub struct Entries<'a> {
+    inner: Inner<'a>,
+    stats: Stats,
+}
+
+impl<'a> Entries<'a> {
+    /// Lists whichever format the header says buf is, checking any checksums along the way
+    pub fn new(buf: &'a [u8]) -> Result<Entries<'a>, Error> {
+        let header = Header::parse(buf)?;
+        verify_trailer(buf, &header)?;
+        Entries::with_header(buf, &header)
+    }
+
+    /// Lists a legacy archive without the header, which has to be told the format
+    pub fn legacy(buf: &'a [u8], format: Format) -> Result<Entries<'a>, Error> {
+        Entries::with_header(buf, &Header::legacy(format))
+    }
+
+    fn with_header(buf: &'a [u8], header: &Header) -> Result<Entries<'a>, Error> {
+        let inner = match header.format {
+            Format::V0 => {
+                let archive = Archive::parse_with(buf, header)?;
+                archive.verify_checksums()?;
+                let dirs = archive.dirs().map(|name| (name, EntryKind::Dir, 0));
+                let files = archive.files().map(|(name, data)| (name, EntryKind::File, data.len() as u64));
+                Inner::V0(Box::new(dirs.chain(files)))
+            },
+            Format::V1 => match Index::parse(buf, header)? {
+                Some(index) => Inner::Index { index, next: 0 },
+                None => Inner::V1 { decoder: Decoder::new(buf).with_header(header), paths: Paths::default() },
+            },
+        };
+        Ok(Entries { inner, stats: Stats::default() })
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>, Error> {
+        match &mut self.inner {
+            Inner::V0(entries) => {
+                let Some((name, kind, size)) = entries.next() else { return Ok(None) };
+                match kind {
+                    EntryKind::Dir => self.stats.dirs += 1,
+                    EntryKind::File => self.stats.files += 1,
+                }
+                self.stats.data_bytes += size;
+                self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+                Ok(Some(make_entry(name.to_bytes(), kind, size)))
+            },
+            Inner::V1 { decoder, paths } => loop {
+                let Some(message) = decoder.next_message()? else { return Ok(None) };
+                message.count(&mut self.stats);
+                let descend = matches!(message, Message::Dir { empty: false, .. });
+                let (name, kind, size) = match message {
+                    Message::File { name, data } => (name, EntryKind::File, data.len() as u64),
+                    Message::Compressed { name, file } => (name, EntryKind::File, file.size),
+                    Message::Dir { name, .. } => (name, EntryKind::Dir, 0),
+                    Message::Pop => {
+                        paths.pop();
+                        continue;
+                    },
+                };
+                let entry = make_entry(paths.push(name.to_bytes()), kind, size);
+                if !descend { paths.pop(); }
+                return Ok(Some(entry));
+            },
+            Inner::Index { index, next } => {
+                if *next == index.len() { return Ok(None); }
+                let entry = index.entry(*next)?;
+                *next += 1;
+                match entry.kind {
+                    EntryKind::Dir => self.stats.dirs += 1,
+                    EntryKind::File => self.stats.files += 1,
+                }
+                self.stats.data_bytes += entry.size;
+                // stats count the names as stored in the messages
+                let name = entry.path.rsplit(|&b| b == b'/').next().unwrap();
+                self.stats.name_bytes += name.len() as u64 + 1;
+                Ok(Some(make_entry(entry.path, entry.kind, entry.size)))
+            },
+        }
+    }
+}
+
+fn make_entry(path: &[u8], kind: EntryKind, size: u64) -> Entry {
+    Entry { path: OsStr::from_bytes(path).into(), kind, size }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
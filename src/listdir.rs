@@ -37,12 +37,12 @@ fn dirent_name_cstr(dirent: &*const libc::dirent) -> &CStr {
     unsafe { CStr::from_ptr(dirent_name_ptr(dirent)) }
 }
 
-struct DIR {
+struct Dir {
     dirp: *mut libc::DIR,
     fd: RawFd, // we have to keep this around so we can do openat
 }
 
-impl Drop for DIR {
+impl Drop for Dir {
     fn drop(&mut self) {
         unsafe {
             let _ = libc::closedir(self.dirp);  // this closes the fd
@@ -58,7 +58,7 @@ fn fdopendir(fd: OwnedFd) -> Result<*mut libc::DIR, Error> {
     Ok(p)
 }
 
-impl DIR {
+impl Dir {
     fn open(path: &Path) -> Result<Self, Error> {
         let fd = opendir(path)?;
         // this calls fcntl F_GETFD to make sure the fd isn't opened with O_PATH
@@ -66,7 +66,7 @@ impl DIR {
         // and it calls stat, so 3 syscalls :(
         let rfd = fd.as_raw_fd();  // smuggle a copy
         let dirp = fdopendir(fd)?;
-        Ok(Self { dirp: dirp, fd: rfd })
+        Ok(Self { dirp, fd: rfd })
     }
 
     fn readdir(&mut self) -> Option<*const libc::dirent> {
@@ -83,11 +83,11 @@ impl DIR {
         };
         let rfd = fd.as_raw_fd(); // smuggle a copy
         let dirp = fdopendir(fd)?;
-        Ok(Self { dirp: dirp, fd: rfd })
+        Ok(Self { dirp, fd: rfd })
     }
 }
 
-fn list_dir_c_rec(curpath: &mut PathBuf, dirp: &mut DIR, dirs: &mut Vec::<OsString>, files: &mut Vec::<OsString>, depth: usize) -> Result<(), Error> {
+fn list_dir_c_rec(curpath: &mut PathBuf, dirp: &mut Dir, dirs: &mut Vec::<OsString>, files: &mut Vec::<OsString>, depth: usize) -> Result<(), Error> {
     if depth > MAX_DIR_DEPTH { return Err(Error::DirTooDeep); }
 
     while let Some(dirent) = dirp.readdir() {
@@ -116,7 +116,7 @@ fn list_dir_c_rec(curpath: &mut PathBuf, dirp: &mut DIR, dirs: &mut Vec::<OsStri
 }
 
 fn list_dir_c(dir: &Path) -> Result<(Vec<OsString>, Vec<OsString>), Error> {
-    let mut dirp = DIR::open(dir)?;
+    let mut dirp = Dir::open(dir)?;
     let mut dirs: Vec::<OsString> = vec![];
     let mut files: Vec::<OsString> = vec![];
     let mut curpath = PathBuf::new();
@@ -295,7 +295,6 @@ fn main() {
         (Some("list_dir_c"), Some(d)) => { list_dir_c(Path::new(d)) },
         (Some("list_dir_nr"), Some(d)) => { list_dir_nr(Path::new(d)) },
         (Some("list_dir_wd"), Some(d)) => { list_dir_wd(Path::new(d)) },
-        (Some(_), None) |
         _ => {
             println!("listdir <list_dir|list_dir2|list_dir_c|list_dir_nr|list_dir_wd> <DIR>");
             return;
@@ -1,40 +1,15 @@
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::env;
-use std::ffi::CStr;
-use std::ffi::OsString;
+use std::ffi::{OsStr,OsString};
 use std::fs::File;
-use std::io::{stdin,BufRead,Write,BufWriter,Seek,SeekFrom};
+use std::io::{stdin,BufRead,Read,Write,BufWriter};
 use std::io;
-use std::os::fd::{FromRawFd,AsRawFd,IntoRawFd,OwnedFd};
 use std::os::unix::prelude::OsStrExt;
-use std::path::Path;
-use std::ptr;
+use std::path::{Path,PathBuf};
 
-use memmap::MmapOptions;
-
-mod common;
-mod open;
-mod liblistdir;
-mod ioringv1;
-
-use liblistdir::{Visitor,list_dir};
-use open::{mkdirat,openpathat,chroot,openpath_at_cwd,openfile_at};
-use common::{Error,read_le_u32,ArchiveFormat1Tag};
-use ioringv1::unpack_v1_ring;
-
-// default fd table size is 64, we 3 + 1 open by default but we don't want to go to fd 257 because
-// that would trigger a realloc and then we waste, so this should always be 4 less than a power of
-// 2. Seems like diminishing returns
-const CLOSE_EVERY: i32 = 256 - 4;
-
-fn join_bytes<'a, I: Iterator<Item = &'a [u8]>>(xs: I) -> Vec<u8> {
-    let mut acc: Vec<u8> = vec![];
-    for x in xs {
-        acc.extend_from_slice(x);
-        acc.push(0)
-    }
-    acc
-}
+use archive_testing::{Codec,Confinement,Entries,EntryKind,Error,Format,Header,OnSkipped,PackOptions,Stats,UnpackOptions,extract,extract_v0,extract_v1,from_cpio,from_tar,pack_cpio,to_tar,pack_v0,pack_v0_dir,unpack,pack_v1,unpack_v0,unpack_v1,unpack_v1_stream,unpack_v1_ring,unpack_v0_ring,ring_support,unpack_v0_parallel,unpack_v1_parallel};
+use archive_testing::common::{align_to_4,mmap_file};
 
 // really just writing this to support cpio to avoid --make-directories
 fn list_dirs(_args: &[String]) {
@@ -65,496 +40,435 @@ fn list_dirs(_args: &[String]) {
     }
 }
 
-fn align_to_4<W: Seek + Write>(writer: &mut W) -> Result<(), Error> {
-    let pos = writer.stream_position().map_err(|_| Error::Align)?;
-    if pos % 4 == 0 { return Ok(()); }
-    let adj = 4 - (pos % 4);
-    for _ in 0..adj { writer.write_all(&[0]).map_err(|_| Error::Align)?; }
-    let pos = writer.stream_position().map_err(|_| Error::Align)?;
-    assert!(pos % 4 == 0);
-    Ok(())
-}
-
 fn make_malicious_archive(args: &[String]) {
-    let outname = args.get(0).ok_or(Error::NoOutfile).unwrap();
+    let outname = args.first().ok_or(Error::NoOutfile).unwrap();
     let outfile = File::create(outname).unwrap();
     let mut outwriter = BufWriter::new(outfile);
+    outwriter.write_all(&Header::new(Format::V0).to_bytes()).unwrap();
     let dirsb = b"../rdir\0/adir\0";
     let filesb = b"../rfile\0/afile\0";
-    for i in vec![2, 2, dirsb.len(), filesb.len()] {
+    for i in [2, 2, dirsb.len(), filesb.len()] {
         outwriter.write_all(&(i as u32).to_le_bytes()).unwrap();
     }
     outwriter.write_all(&dirsb[..]).unwrap();
     outwriter.write_all(&filesb[..]).unwrap();
     align_to_4(&mut outwriter).unwrap();
-    for size in vec![0, 0] {
-        outwriter.write_all(&(size as u32).to_le_bytes()).unwrap();
+    for size in [0u32, 0] {
+        outwriter.write_all(&size.to_le_bytes()).unwrap();
     }
 }
 
-/// v0 archive format
-/// num_dirs: u32le
-/// num_files: u32le
-/// dirnames_size: u32le
-/// filenames_size: u32le
-/// <dirnames with null bytes> of length dirnames_size bytes
-/// <filenames with null bytes> of length filenames_size bytes
-/// 0-3 padding bytes to align file_sizes up to 4 byte alignment
-/// <num_files x u32le file sizes> of length num_files * 4 bytes
-/// <data>
-/// ---
-/// input is line separated pathnames relative to cwd
-/// ---
-/// args <output file>
-fn pack_v0(args: &[String]) {
-    let outname = args.get(0).ok_or(Error::NoOutfile).unwrap();
-    let outfile = File::create(outname).unwrap();
-    let mut outwriter = BufWriter::new(outfile);
-    let files = {
-        let mut acc: Vec<_> = stdin().lock().lines().map(|x| x.unwrap()).collect();
-        acc.sort();
-        acc
-    };
-    let mut sizes = vec![];
-    let mut size = 0;
-    let dirs = {
-        let mut acc = HashSet::new();
-        let empty = OsString::new();
-        for file in &files {
-            let p = Path::new(&file);
-            if !p.is_file() { continue; }
-            let file_len = p.metadata().unwrap().len();
-            sizes.push(file_len);
-            size += file_len;
-            assert!(p.is_file(), "{:?} is not file", p);
-            for parent in p.ancestors().skip(1) {
-                if parent != empty {
-                    acc.insert(parent.to_owned());
-                }
-            }
+fn exit_on_err<T>(cmd: &str, res: Result<T, Error>) -> T {
+    match res {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{cmd} failed: {e}");
+            std::process::exit(1);
         }
-        let mut acc: Vec<_> = acc.drain().collect();
-        acc.sort();
-        acc
-    };
-    let filesb = join_bytes(files.iter().map(|x| x.as_bytes()));
-    let dirsb = join_bytes(dirs.iter().map(|x| x.as_os_str().as_bytes()));
-    println!("there are {} dirs", dirs.len());
-    println!("there are {} files", files.len());
-    println!("total bytes of data {}", size);
-    println!("filenames len {}", filesb.len());
-    println!("writing to {}", outname);
-    println!("dirsb len {}", dirsb.len());
-    for i in vec![dirs.len(), files.len(), dirsb.len(), filesb.len()] {
-        outwriter.write_all(&(i as u32).to_le_bytes()).unwrap();
-    }
-    outwriter.write_all(&dirsb).unwrap();
-    outwriter.write_all(&filesb).unwrap();
-    align_to_4(&mut outwriter).unwrap();
-
-    {
-        let filesizes_start = outwriter.stream_position().unwrap();
-        println!("filesizes_start={filesizes_start}");
-    }
-    for size in sizes {
-        outwriter.write_all(&(size as u32).to_le_bytes()).unwrap();
-    }
-    for file in &files {
-        let mut f = File::open(file).unwrap();
-        io::copy(&mut f, &mut outwriter).unwrap();
     }
 }
 
-/// v1 archive format
-/// message+
-/// message =
-///   | file: <tag> <name zero term> <u32le> <blob>
-///   | dir:  <tag> <name zero term>
-///   | pop:  <tag>
-///
-/// alternate format would be to buffer the names and sizes and just dump
-/// the blob data so, this avoids the write per message but requires buffering
-/// <blob size> <blob data> <message+>
-/// message =
-///   | file: <tag> <name zero term> <u32le>
-///   | dir:  <tag> <name zero term>
-///   | pop:  <tag>
-///
-/// on the decode side, we'll probably mmap it so not much different
-
-
-// So i intended to have this generic over a writer so you could eg test with a vec, but then idk
-// how to do the specialization for files when we want to use sendfile; I think io::copy does this
-// appropriately but the 99% case is for files, so just do that
-// struct MyVisitor<W: Write> {
-//     writer: BufWriter::<W>,
-// }
-//
-// impl<W: Write> MyVisitor<W> {
-//     fn new(out: W) -> MyVisitor<W> {
-//         MyVisitor { writer: BufWriter::<W>::new(out), }
-//     }
-//
-//     fn into_writer(self) -> W {
-//         self.writer.into_inner().map_err(|_| Error::Write).unwrap()
-//     }
-// }
-
-struct MyVisitor {
-    writer: BufWriter::<File>,
+fn print_stats(stats: &Stats) {
+    println!("there are {} dirs", stats.dirs);
+    println!("there are {} files", stats.files);
+    println!("total bytes of data {}", stats.data_bytes);
+    println!("names len {}", stats.name_bytes);
 }
 
-impl MyVisitor {
-    fn new(out: File) -> MyVisitor {
-        MyVisitor { writer: BufWriter::new(out) }
-    }
-
-    fn into_file(self) -> File {
-        self.writer.into_inner().map_err(|_| Error::Write).unwrap()
+/// trailing args of the pack commands
+///   checksums, index: see PackOptions
+///   zstd, lz4: set PackOptions::compression (v1 only)
+fn pack_options(flags: &[String]) -> PackOptions {
+    let mut opts = PackOptions::default();
+    for flag in flags {
+        match flag.as_str() {
+            "checksums" => { opts.checksums = true; },
+            "index" => { opts.index = true; },
+            "zstd" => { opts.compression = Some(Codec::Zstd); },
+            "lz4" => { opts.compression = Some(Codec::Lz4); },
+            _ => {
+                eprintln!("unknown option {flag}");
+                std::process::exit(1);
+            }
+        }
     }
+    opts
 }
 
-// the File::metadata.len() uses statx with STATX_ALL
-// the statx struct is much bigger than stat and even with masking
-// it still does a copy of the whole thing
-#[allow(dead_code)]
-fn file_size_statx<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
-    use std::mem;
-    let size = unsafe {
-        let empty_path = c"";
-        let mut buf: libc::statx = mem::zeroed();
-        let ret = libc::statx(
-            fd.as_raw_fd(), empty_path.as_ptr(),
-            libc::AT_STATX_SYNC_AS_STAT | libc::AT_EMPTY_PATH,
-            libc::STATX_SIZE,
-            &mut buf as *mut _
-        );
-        if ret < 0 { return Err(Error::Statx); }
-        buf.stx_size
+/// input is newline separated pathnames relative to cwd, or NUL separated (find -print0) with
+/// null. Names are bytes so they don't have to be UTF-8, but only null can take names with
+/// newlines. With dir=<input dir> the tree under it is packed instead and stdin isn't read
+/// args <output file> [dir=<input dir>] [null] [checksums]
+fn pack_v0_cmd(args: &[String]) {
+    let outname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let mut indir = None;
+    let mut separator = b'\n';
+    let mut flags = vec![];
+    for arg in &args[1..] {
+        if let Some(dir) = arg.strip_prefix("dir=") {
+            indir = Some(PathBuf::from(dir));
+        } else if arg == "null" {
+            separator = 0;
+        } else {
+            flags.push(arg.clone());
+        }
+    }
+    let opts = pack_options(&flags);
+    let outfile = File::create(outname).unwrap();
+    println!("writing to {}", outname);
+    let res = match indir {
+        Some(indir) => pack_v0_dir(&indir, outfile, &opts),
+        None => {
+            let mut input = vec![];
+            stdin().lock().read_to_end(&mut input).unwrap();
+            let files: Vec<&Path> = input.split(|&b| b == separator)
+                .filter(|x| !x.is_empty())
+                // find prints ./name unless it is given -printf '%P'
+                .map(|x| Path::new(OsStr::from_bytes(x.strip_prefix(b"./").unwrap_or(x))))
+                .collect();
+            pack_v0(Path::new("."), &files, outfile, &opts)
+        },
     };
-    Ok(size)
+    let stats = exit_on_err("pack_v0", res);
+    print_stats(&stats);
 }
 
-fn file_size_fstat<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
-    use std::mem;
-    let size = unsafe {
-        let mut buf: libc::stat = mem::zeroed();
-        let ret = libc::fstat(
-            fd.as_raw_fd(),
-            &mut buf as *mut _
-        );
-        if ret < 0 { return Err(Error::Fstat); }
-        buf.st_size
+/// args: <input dir> <output file or - for stdout> [checksums] [index] [zstd|lz4]
+fn pack_v1_cmd(args: &[String]) {
+    let indir = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = pack_options(&args[2..]);
+    let res = if outname == "-" {
+        pack_v1(Path::new(indir), io::stdout().lock(), &opts)
+    } else {
+        pack_v1(Path::new(indir), File::create(outname).unwrap(), &opts)
     };
-    // dude st_size is signed here and unsigned in statx
-    size.try_into().map_err(|_| Error::Fstat)
+    exit_on_err("pack_v1", res);
 }
 
-fn file_size<Fd: AsRawFd>(fd: &Fd) -> Result<u64, Error> {
-    //file_size_statx(fd)
-    file_size_fstat(fd)
-}
-
-// TODO how to pass errors back through appropriately?
-//impl<W: Write + Seek> Visitor for MyVisitor<W> {
-impl Visitor for MyVisitor {
-    fn on_file(&mut self, name: &CStr, mut file: File) -> () {
-        //let len = file.metadata().unwrap().len();
-        let len = file_size(&file).unwrap();
-
-        // self.buf.clear();
-        // self.buf.push(ArchiveFormat1Tag::File as u8);
-        // self.buf.extend_from_slice(name.to_bytes_with_nul());
-        // self.buf.extend_from_slice(&(len as u32).to_le_bytes());
-        // self.out.write_all(self.buf.as_slice()).unwrap();
-
-        self.writer.write_all(&[ArchiveFormat1Tag::File as u8]).unwrap();
-        self.writer.write_all(name.to_bytes_with_nul()).unwrap();
-        self.writer.write_all(&(len as u32).to_le_bytes()).unwrap();
-        self.writer.flush().unwrap();
-
-        // TODO io::copy tries a copy_file_range first then falls back to sendfile when the two fds
-        // are on different filesystems; this is happening now b/c I'm going from a disk to tmpfs
-        // but my initial testing was tmpfs -> tmpfs , inside the vm it will be pmem -> tmpfs so
-        // I think we'll want to instead always use sendfile (if we were to go that route, but
-        // write seems good enough)
-        // docs say it is inadvisable to write through get_mut, but ...
-        let outfile = self.writer.get_mut();
-        // io::copy(&mut file, outfile).unwrap();
-        // TODO maybe configurable whether to use copy_file_range or sendfile
-        sendfile_all(&mut file, outfile, len).unwrap();
+/// trailing args of the unpack commands
+///   copy_file_range: see UnpackOptions (v0 only)
+///   openat2: use Confinement::Openat2 instead of chroot
+///   legacy: the archive is from before the header existed
+///   verify_only: check the archive (including any checksums) without writing anything
+///   ring_depth=N fixed_buffers sqpoll single_issuer defer_taskrun coop_taskrun: see RingOptions
+///   (unpack_v0_ring and unpack_v1_ring only)
+///   threads=N: see UnpackOptions (unpack_v0_parallel and unpack_v1_parallel only)
+///   max_total_bytes=N max_file_size=N max_files=N max_dirs=N max_name_len=N max_depth=N: see
+///   UnpackLimits
+fn unpack_options(flags: &[String]) -> UnpackOptions {
+    let mut opts = UnpackOptions::default();
+    for flag in flags {
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => match value.parse::<u64>() {
+                Ok(value) => (key, value),
+                Err(_) => {
+                    eprintln!("bad value in option {flag}");
+                    std::process::exit(1);
+                }
+            },
+            None => (flag.as_str(), 0),
+        };
+        let limits = &mut opts.limits;
+        match key {
+            "copy_file_range" => { opts.copy_file_range = true; },
+            "openat2" => { opts.confinement = Confinement::Openat2; },
+            "legacy" => { opts.legacy = true; },
+            "verify_only" => { opts.verify_only = true; },
+            "ring_depth" => { opts.ring.depth = value as usize; },
+            "fixed_buffers" => { opts.ring.fixed_buffers = true; },
+            "sqpoll" => { opts.ring.sqpoll = true; },
+            "single_issuer" => { opts.ring.single_issuer = true; },
+            "defer_taskrun" => { opts.ring.defer_taskrun = true; },
+            "coop_taskrun" => { opts.ring.coop_taskrun = true; },
+            "threads" => { opts.threads = value as usize; },
+            "max_total_bytes" => { limits.max_total_bytes = value; },
+            "max_file_size" => { limits.max_file_size = value; },
+            "max_files" => { limits.max_files = value as usize; },
+            "max_dirs" => { limits.max_dirs = value as usize; },
+            "max_name_len" => { limits.max_name_len = value as usize; },
+            "max_depth" => { limits.max_depth = value as usize; },
+            _ => {
+                eprintln!("unknown option {flag}");
+                std::process::exit(1);
+            }
+        }
     }
+    opts
+}
 
-    fn on_dir(&mut self, name: &CStr) -> () {
-        // self.buf.clear();
-        // self.buf.push(ArchiveFormat1Tag::Dir as u8);
-        // self.buf.extend_from_slice(name.to_bytes_with_nul());
-        // self.out.write_all(self.buf.as_slice()).unwrap();
-
-        self.writer.write_all(&[ArchiveFormat1Tag::Dir as u8]).unwrap();
-        self.writer.write_all(name.to_bytes_with_nul()).unwrap();
-    }
+/// args <infile> <output dir> [copy_file_range] [openat2] [max_*=N]
+///   unpacks v0 or v1 depending on the header
+fn unpack_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack", unpack(&infile, Path::new(outname), &opts));
+}
 
-    fn leave_dir(&mut self) -> () {
-        //self.out.write_all(&[ArchiveFormat1Tag::Pop as u8]).unwrap();
-        self.writer.write_all(&[ArchiveFormat1Tag::Pop as u8]).unwrap();
-    }
+/// args <infile> [max_*=N]
+///   checks a v0 or v1 archive, including any checksums, without writing anything
+fn verify_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let mut opts = unpack_options(&args[1..]);
+    opts.verify_only = true;
+    let infile = File::open(inname).unwrap();
+    let stats = exit_on_err("verify", unpack(&infile, Path::new(""), &opts));
+    print_stats(&stats);
 }
 
-/// args: <input dir> <output file>
-fn pack_v1(args: &[String]) {
-    let indir = args.get(0).ok_or(Error::NoOutfile).unwrap();
+/// args <infile> <output dir> [copy_file_range] [openat2] [legacy] [max_*=N]
+///   <output dir> should be empty
+fn unpack_v0_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
     let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
-    let indirpath = Path::new(indir);
-    assert!(indirpath.is_dir(), "{:?} should be a dir", indirpath);
-    let fileout = File::create(outname).unwrap();
-    let mut visitor = MyVisitor::new(fileout);
-    list_dir(indirpath, &mut visitor).unwrap();
-    let outfile = visitor.into_file();
-    let _len = outfile.metadata().unwrap().len();
-    // println!("outfile has total len={len}");
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v0", unpack_v0(&infile, Path::new(outname), &opts));
 }
 
-// TODO these are semi duplicated with stuff in liblistdir
-fn unpack_v1(args: &[String]) {
-    let inname = args.get(0).ok_or(Error::NoOutfile).unwrap();
+/// args <infile> <output dir> [openat2] [legacy] [max_*=N]
+fn unpack_v1_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
     let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v1", unpack_v1(&infile, Path::new(outname), &opts));
+}
 
-    let inpath = Path::new(&inname);
-    let outpath = Path::new(&outname);
-    assert!(inpath.is_file(), "{:?} should be a file", inpath);
-    assert!(outpath.is_dir(), "{:?} should be a dir", outpath);
-
-    let infile = File::open(inpath).unwrap();
-    let mmap = unsafe { MmapOptions::new().map(&infile).unwrap() };
-
-    chroot(&outpath);
-
-    let mut stack: Vec<OwnedFd> = Vec::with_capacity(32);  // always non-empty
-    stack.push(openpath_at_cwd(c".").unwrap());
+/// args <infile> <output dir> [openat2] [legacy] [ring_depth=N] [ring flags] [max_*=N]
+fn unpack_v0_ring_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v0_ring", unpack_v0_ring(&infile, Path::new(outname), &opts));
+}
 
-    let mut cur = &mmap[..];
-    loop {
-        match cur.get(0).map(|x| x.try_into()) {
-            Some(Ok(ArchiveFormat1Tag::File)) => {
-                cur = &cur[1..];
-                let parent = stack.last().unwrap();
-                let name = unsafe { CStr::from_bytes_with_nul_unchecked(cur) };
-                let fd = openfile_at(parent, name, libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC).unwrap();
-                let mut file = unsafe { File::from_raw_fd(fd.into_raw_fd()) };
-                let zbi = cur.iter().position(|&x| x == 0).unwrap(); // todo do better
-                cur = &cur[zbi+1..];
-                let len = read_le_u32(&mut cur) as usize;
-                file.write_all(&cur[..len]).unwrap();
-                cur = &cur[len..];
-            },
-            Some(Ok(ArchiveFormat1Tag::Dir)) => {
-                cur = &cur[1..];
-                let parent = stack.last().unwrap();
-                let name = unsafe { CStr::from_bytes_with_nul_unchecked(cur) };
-                mkdirat(parent, name).unwrap();
-                let zbi = cur.iter().position(|&x| x == 0).unwrap(); // todo do better
-                cur = &cur[zbi+1..];
-                if *cur.get(0).unwrap() == (ArchiveFormat1Tag::Pop as u8) {
-                    // fast path for empty dir, never open the dir and push it
-                    cur = &cur[1..];
-                } else {
-                    let fd = openpathat(parent, name).unwrap();
-                    stack.push(fd);
-                }
-            },
-            Some(Ok(ArchiveFormat1Tag::Pop)) => {
-                cur = &cur[1..];
-                // always expected to be nonempty, todo handle gracefully for malicious archives
-                stack.pop().unwrap();
-            },
-            Some(Err(_)) => {
-                let b = cur[0];
-                panic!("oh no got bad tag byte {b}");
-            },
-            None => {
-                break;
-            }
-        }
-    }
+/// args <infile> <output dir> [openat2] [legacy] [ring_depth=N] [ring flags] [max_*=N]
+fn unpack_v1_ring_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v1_ring", unpack_v1_ring(&infile, Path::new(outname), &opts));
 }
 
-fn as_slice<T>(data: &[u8]) -> Option<&[T]> {
-    let len = data.len();
-    let ptr = data.as_ptr();
-    let align = std::mem::align_of::<T>();
-    if len % align != 0 { return None; }
-    if (ptr as usize) % align != 0 { return None; }
-    unsafe {
-        let ptr = ptr as *const T;
-        Some(std::slice::from_raw_parts(ptr, len / align))
-    }
+/// args <infile> <output dir> [openat2] [legacy] [threads=N] [max_*=N]
+fn unpack_v0_parallel_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v0_parallel", unpack_v0_parallel(&infile, Path::new(outname), &opts));
 }
 
-fn copy_file_range_all(filein: &mut File, fileout: &mut File, len: u64) -> Result<(), Error> {
-    let fd_in  = filein.as_raw_fd();
-    let fd_out = fileout.as_raw_fd();
-    let mut len = len;
-    while len > 0 {
-        let ret = unsafe {
-            libc::copy_file_range(fd_in, ptr::null_mut(), fd_out, ptr::null_mut(), len as usize, 0)
-        };
-        if ret <= 0 { return Err(Error::CopyFileRange); }
-        let ret = ret as u64;
-        if ret > len { return Err(Error::CopyFileRange); }
-        len -= ret;
-    }
-    Ok(())
+/// args <infile> <output dir> [openat2] [legacy] [threads=N] [max_*=N]
+fn unpack_v1_parallel_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let opts = unpack_options(&args[2..]);
+    let infile = File::open(inname).unwrap();
+    exit_on_err("unpack_v1_parallel", unpack_v1_parallel(&infile, Path::new(outname), &opts));
 }
 
-fn sendfile_all(filein: &mut File, fileout: &mut File, len: u64) -> Result<(), Error> {
-    let fd_in  = filein.as_raw_fd();
-    let fd_out = fileout.as_raw_fd();
-    let mut len = len;
-    while len > 0 {
-        let ret = unsafe {
-            libc::sendfile(fd_out, fd_in, ptr::null_mut(), len as usize)
-        };
-        if ret <= 0 { return Err(Error::CopyFileRange); }
-        let ret = ret as u64;
-        if ret > len { return Err(Error::CopyFileRange); }
-        len -= ret;
-    }
-    Ok(())
+/// args [ring flags]
+///   prints which ring setup flags the running kernel takes, and whether it takes the ones given
+///   together. Exits with 1 if it doesn't
+fn ring_support_cmd(args: &[String]) {
+    let opts = unpack_options(args);
+    let support = ring_support(&opts.ring);
+    let yes_no = |x: bool| if x { "yes" } else { "no" };
+    println!("sqpoll: {}", yes_no(support.sqpoll));
+    println!("single_issuer: {}", yes_no(support.single_issuer));
+    println!("defer_taskrun: {}", yes_no(support.defer_taskrun));
+    println!("coop_taskrun: {}", yes_no(support.coop_taskrun));
+    println!("fixed_buffers: {}", yes_no(support.fixed_buffers));
+    println!("requested: {}", yes_no(support.requested));
+    if !support.requested { std::process::exit(1); }
 }
 
-/// args <infile> <output dir>
-///   <output dir> should be empty
-fn unpack_v0(args: &[String]) {
-    let inname = args.get(0).ok_or(Error::NoOutfile).unwrap();
+/// args <infile or - for stdin> <output dir> [openat2] [legacy] [max_*=N]
+fn unpack_v1_stream_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
     let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
-    let use_copy_file = {
-        if let Some(s) = args.get(2) {
-            s == "copy_file_range"
-        } else {
-            false
+    let opts = unpack_options(&args[2..]);
+    let res = if inname == "-" {
+        unpack_v1_stream(io::stdin().lock(), Path::new(outname), &opts)
+    } else {
+        unpack_v1_stream(File::open(inname).unwrap(), Path::new(outname), &opts)
+    };
+    exit_on_err("unpack_v1_stream", res);
+}
+
+/// args <infile> <path> <output file or - for stdout> [v0|v1]
+///   writes out the one file stored as <path>. The format is from the header, or given for legacy
+///   archives
+fn extract_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let path = Path::new(args.get(1).ok_or(Error::NoOutfile).unwrap());
+    let outname = args.get(2).ok_or(Error::NoOutfile).unwrap();
+    let infile = File::open(inname).unwrap();
+    let mmap = exit_on_err("extract", mmap_file(&infile));
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let data = match args.get(3).map(|s| s.as_str()) {
+        None => extract(buf, path),
+        Some("v0") => extract_v0(buf, path, true).map(Cow::Borrowed),
+        Some("v1") => extract_v1(buf, path, true),
+        Some(format) => {
+            eprintln!("unknown format {format}");
+            std::process::exit(1);
         }
     };
-    //println!("use_copy_file={}", use_copy_file);
-    let inpath = Path::new(&inname);
-    let outpath = Path::new(&outname);
-    assert!(inpath.is_file(), "{:?} should be a file", inpath);
-    assert!(outpath.is_dir(), "{:?} should be a dir", outpath);
-    let mut infile = File::open(inpath).unwrap();
-    let mmap = unsafe { MmapOptions::new().map(&infile).unwrap() };
-    let (num_dirs, num_files, dirnames_size, filenames_size) = {
-        (
-            u32::from_le_bytes(mmap[0..4].try_into().unwrap()) as usize,
-            u32::from_le_bytes(mmap[4..8].try_into().unwrap()) as usize,
-            u32::from_le_bytes(mmap[8..12].try_into().unwrap()) as usize,
-            u32::from_le_bytes(mmap[12..16].try_into().unwrap()) as usize,
-        )
+    let data = exit_on_err("extract", data);
+    let res = if outname == "-" {
+        io::stdout().lock().write_all(&data)
+    } else {
+        File::create(outname).and_then(|mut out| out.write_all(&data))
     };
-    let dirnames_start = 4 * 4;
-    let filenames_start = dirnames_start + dirnames_size;
-    let filesizes_start = {
-        let mut x = filenames_start + filenames_size;
-        if x % 4 != 0 {
-            let adj = 4 - (x % 4);
-            x += adj;
+    exit_on_err("extract", res.map_err(|_| Error::Write));
+}
+
+/// args <infile> [v0|v1]
+///   prints one line per entry: type, size, path; then the stats. The format is from the header,
+///   or given for legacy archives
+fn list_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let infile = File::open(inname).unwrap();
+    let mmap = exit_on_err("list", mmap_file(&infile));
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let entries = match args.get(1).map(|s| s.as_str()) {
+        None => Entries::new(buf),
+        Some("v0") => Entries::legacy(buf, Format::V0),
+        Some("v1") => Entries::legacy(buf, Format::V1),
+        Some(format) => {
+            eprintln!("unknown format {format}");
+            std::process::exit(1);
         }
-        x
     };
-    assert!(filesizes_start % 4 == 0, "filesizes_start={}", filesizes_start);
-    let data_start = filesizes_start + (4 * num_files);
-
-    chroot(&outpath);
+    let mut entries = exit_on_err("list", entries);
+    let mut out = BufWriter::new(io::stdout().lock());
+    for entry in entries.by_ref() {
+        let entry = exit_on_err("list", entry);
+        let kind = match entry.kind {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+        };
+        writeln!(out, "{kind:4} {:>10} {}", entry.size, entry.path.display()).unwrap();
+    }
+    out.flush().unwrap();
+    drop(out);
+    print_stats(entries.stats());
+}
 
-    {
-        let mut dirnames_cur = &mmap[dirnames_start..filenames_start];
-        for _ in 0..num_dirs {
-            unsafe {
-                let ret = libc::mkdir(dirnames_cur.as_ptr() as *const i8, 0o755);
-                assert!(ret == 0, "mkdir failed");
-            }
-            // idk is it better to do dirnames_buf.split(0)?
-            let zbi = dirnames_cur.iter().position(|&x| x == 0).unwrap();
-            dirnames_cur = &dirnames_cur[zbi+1..];
+fn parse_format(arg: Option<&String>) -> Format {
+    match arg.map(|s| s.as_str()) {
+        Some("v0") => Format::V0,
+        Some("v1") => Format::V1,
+        format => {
+            eprintln!("unknown format {format:?}, expected v0 or v1");
+            std::process::exit(1);
         }
     }
+}
 
-    // kinda ugly
-    if use_copy_file {
-        let mut filenames_cur = &mmap[filenames_start..filesizes_start];
-        let filesizes = as_slice::<u32>(&mmap[filesizes_start..data_start]).unwrap();
-        assert!(filesizes.len() == num_files);
-        infile.seek(SeekFrom::Start(data_start as u64)).unwrap();
-        for size in filesizes {
-            let size = *size as u64;
-            let mut fileout = unsafe {
-                let fd = libc::open(filenames_cur.as_ptr() as *const i8, libc::O_CREAT | libc::O_WRONLY, 0o755);
-                assert!(fd > 0, "open failed");
-                File::from_raw_fd(fd)
-            };
-            // hmm why didn't i use io::copy here originally?
-            copy_file_range_all(&mut infile, &mut fileout, size).unwrap();
-            let zbi = filenames_cur.iter().position(|&x| x == 0).unwrap();
-            filenames_cur = &filenames_cur[zbi+1..];
-        };
+/// args <input tar or cpio> <output file> <v0|v1> [strict] [pack options]
+///   anything but regular files and dirs is listed on stderr and left out, or with strict fails
+///   the conversion
+fn convert_cmd(cmd: &str, args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let format = parse_format(args.get(2));
+    let strict = args.get(3).is_some_and(|x| x == "strict");
+    let on_skipped = if strict { OnSkipped::Fail } else { OnSkipped::Report };
+    let opts = pack_options(&args[if strict { 4 } else { 3 }..]);
+    let infile = File::open(inname).unwrap();
+    let mmap = exit_on_err(cmd, mmap_file(&infile));
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let outfile = File::create(outname).unwrap();
+    let res = match cmd {
+        "from_tar" => from_tar(buf, format, outfile, &opts, on_skipped),
+        _ => from_cpio(buf, format, outfile, &opts, on_skipped),
+    };
+    let converted = exit_on_err(cmd, res);
+    for skipped in &converted.skipped {
+        eprintln!("skipped {:?} {} at offset {}", skipped.kind, skipped.path.display(), skipped.offset);
+    }
+    print_stats(&converted.stats);
+}
 
+/// args <input file> <output tar or - for stdout>
+fn to_tar_cmd(args: &[String]) {
+    let inname = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let infile = File::open(inname).unwrap();
+    let mmap = exit_on_err("to_tar", mmap_file(&infile));
+    let buf = mmap.as_deref().unwrap_or(&[]);
+    let res = if outname == "-" {
+        to_tar(buf, BufWriter::new(io::stdout().lock()))
     } else {
-        let mut filenames_cur = &mmap[filenames_start..filesizes_start];
-        let filesizes = as_slice::<u32>(&mmap[filesizes_start..data_start]).unwrap();
-        assert!(filesizes.len() == num_files);
-        let mut data_cur = &mmap[data_start..];
-
-        let mut close_every: i32 = CLOSE_EVERY;
-
-        for size in filesizes {
-            let size = *size as usize;
-            let mut fileout = unsafe {
-                let fd = libc::open(filenames_cur.as_ptr() as *const i8, libc::O_CREAT | libc::O_WRONLY, 0o755);
-                assert!(fd > 0, "open failed");
-                File::from_raw_fd(fd)
-            };
-            let data = &data_cur[..size];
-            assert!(data.len() == size);
-            fileout.write_all(data).unwrap();
-            data_cur = &data_cur[size..];
-
-            let _ = fileout.into_raw_fd();
-            close_every -= 1;
-            if close_every == 0 {
-                unsafe {
-                    // TODO if this was in a lib we'd want to figure out our current fd that we'll
-                    // go into and/or verify there aren't any random fds above us but not sure you
-                    // can do that well so maybe this is only a go if we're a standalone exe
-                    libc::close_range(4, std::u32::MAX, 0);
-                }
-                close_every = CLOSE_EVERY;
-            }
-
-            let zbi = filenames_cur.iter().position(|&x| x == 0).unwrap();
-            filenames_cur = &filenames_cur[zbi+1..];
-        }
-    }
+        to_tar(buf, BufWriter::new(File::create(outname).unwrap()))
+    };
+    exit_on_err("to_tar", res);
+}
 
-    // TODO if this was in a lib we'd want to do another libc::close_range(4, std::u32::MAX, 0)
-    // here
+/// args: <input dir> <output file or - for stdout>
+fn pack_cpio_cmd(args: &[String]) {
+    let indir = args.first().ok_or(Error::NoOutfile).unwrap();
+    let outname = args.get(1).ok_or(Error::NoOutfile).unwrap();
+    let res = if outname == "-" {
+        pack_cpio(Path::new(indir), io::stdout().lock())
+    } else {
+        pack_cpio(Path::new(indir), File::create(outname).unwrap())
+    };
+    exit_on_err("pack_cpio", res);
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     match args.get(1).map(|s| s.as_str()) {
-        Some("pack_v0") => { pack_v0(&args[2..]); },
-        Some("pack_v1") => { pack_v1(&args[2..]); },
-        Some("unpack_v0") => { unpack_v0(&args[2..]); },
-        Some("unpack_v1") => { unpack_v1(&args[2..]); },
-        Some("unpack_v1_ring") => { unpack_v1_ring(&args[2..]); },
+        Some("pack_v0") => { pack_v0_cmd(&args[2..]); },
+        Some("pack_v1") => { pack_v1_cmd(&args[2..]); },
+        Some("unpack") => { unpack_cmd(&args[2..]); },
+        Some("unpack_v0") => { unpack_v0_cmd(&args[2..]); },
+        Some("unpack_v1") => { unpack_v1_cmd(&args[2..]); },
+        Some("unpack_v1_stream") => { unpack_v1_stream_cmd(&args[2..]); },
+        Some("unpack_v1_ring") => { unpack_v1_ring_cmd(&args[2..]); },
+        Some("unpack_v0_ring") => { unpack_v0_ring_cmd(&args[2..]); },
+        Some("unpack_v0_parallel") => { unpack_v0_parallel_cmd(&args[2..]); },
+        Some("unpack_v1_parallel") => { unpack_v1_parallel_cmd(&args[2..]); },
+        Some("ring_support") => { ring_support_cmd(&args[2..]); },
+        Some("verify") => { verify_cmd(&args[2..]); },
+        Some("extract") => { extract_cmd(&args[2..]); },
+        Some("list") => { list_cmd(&args[2..]); },
+        Some("from_tar") => { convert_cmd("from_tar", &args[2..]); },
+        Some("from_cpio") => { convert_cmd("from_cpio", &args[2..]); },
+        Some("pack_cpio") => { pack_cpio_cmd(&args[2..]); },
+        Some("to_tar") => { to_tar_cmd(&args[2..]); },
         Some("list_dirs") => { list_dirs(&args[2..]); },
         Some("make_malicious") => { make_malicious_archive(&args[2..]); },
         _ => {
             println!("got args={args:?}");
-            println!("pack_v0 <output-file> < <file-list>");
-            println!("pack_v1 <input-dir> <output-file>");
-            println!("unpack_v0 <input-file> <output-dir> [copy_file_range]");
-            println!("unpack_v1 <input-file> <output-dir>");
-            println!("unpack_v1_ring <input-file> <output-dir>");
+            println!("pack_v0 <output-file> [null] [checksums] < <file-list>");
+            println!("pack_v0 <output-file> dir=<input-dir> [checksums]");
+            println!("pack_v1 <input-dir> <output-file|-> [checksums] [index] [zstd|lz4]");
+            println!("unpack <input-file> <output-dir> [copy_file_range] [openat2] [max_*=N]");
+            println!("unpack_v0 <input-file> <output-dir> [copy_file_range] [openat2] [legacy] [max_*=N]");
+            println!("unpack_v1 <input-file> <output-dir> [openat2] [legacy] [max_*=N]");
+            println!("unpack_v1_stream <input-file|-> <output-dir> [openat2] [legacy] [max_*=N]");
+            println!("unpack_v1_ring <input-file> <output-dir> [openat2] [legacy] [ring_depth=N] [ring flags] [max_*=N]");
+            println!("unpack_v0_ring <input-file> <output-dir> [openat2] [legacy] [ring_depth=N] [ring flags] [max_*=N]");
+            println!("unpack_v0_parallel <input-file> <output-dir> [openat2] [legacy] [threads=N] [max_*=N]");
+            println!("unpack_v1_parallel <input-file> <output-dir> [openat2] [legacy] [threads=N] [max_*=N]");
+            println!("ring_support [fixed_buffers] [sqpoll] [single_issuer] [defer_taskrun] [coop_taskrun]");
+            println!("verify <input-file> [max_*=N]");
+            println!("extract <input-file> <path> <output-file|-> [v0|v1 for legacy archives]");
+            println!("list <input-file> [v0|v1 for legacy archives]");
+            println!("from_tar <input-tar> <output-file> <v0|v1> [strict] [checksums] [index] [zstd|lz4]");
+            println!("to_tar <input-file> <output-tar|->");
+            println!("from_cpio <input-cpio> <output-file> <v0|v1> [strict] [checksums] [index] [zstd|lz4]");
+            println!("pack_cpio <input-dir> <output-file|->");
             println!("list_dirs < <file-list>");
         }
     }
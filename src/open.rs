@@ -6,26 +6,33 @@ use std::io::Write;
 
 use crate::common::Error;
 
-pub fn chroot(dir: &Path) {
+/// unshare into a new user namespace mapping us to root, then chroot into dir. This changes
+/// process global state (uid and root) and can't be undone
+pub fn chroot(dir: &Path) -> Result<(), Error> {
     use std::os::unix::fs;
     let uid = unsafe { libc::geteuid() };
     let gid = unsafe { libc::getegid() };
     unsafe {
         let ret = libc::unshare(libc::CLONE_NEWUSER);
-        assert!(ret == 0, "unshare fail");
+        if ret < 0 { return Err(Error::Chroot); }
     }
-    File::create("/proc/self/uid_map").unwrap()
-        .write_all(format!("0 {} 1", uid).as_bytes()).unwrap();
-    File::create("/proc/self/setgroups").unwrap().write_all(b"deny").unwrap();
-    File::create("/proc/self/gid_map").unwrap()
-        .write_all(format!("0 {} 1", gid).as_bytes()).unwrap();
-    fs::chroot(dir).unwrap();
-    std::env::set_current_dir("/").unwrap();
+    File::create("/proc/self/uid_map")
+        .and_then(|mut f| f.write_all(format!("0 {} 1", uid).as_bytes()))
+        .map_err(|_| Error::Chroot)?;
+    File::create("/proc/self/setgroups")
+        .and_then(|mut f| f.write_all(b"deny"))
+        .map_err(|_| Error::Chroot)?;
+    File::create("/proc/self/gid_map")
+        .and_then(|mut f| f.write_all(format!("0 {} 1", gid).as_bytes()))
+        .map_err(|_| Error::Chroot)?;
+    fs::chroot(dir).map_err(|_| Error::Chroot)?;
+    std::env::set_current_dir("/").map_err(|_| Error::Chroot)?;
+    Ok(())
 }
 
 
 pub fn opendir(dir: &Path) -> Result<OwnedFd, Error> {
-    let cstr = CString::new(dir.as_os_str().as_encoded_bytes()).unwrap();
+    let cstr = CString::new(dir.as_os_str().as_encoded_bytes()).map_err(|_| Error::Open)?;
     let fd = unsafe {
         let ret = libc::open(cstr.as_ptr(), libc::O_DIRECTORY | libc::O_RDONLY | libc::O_CLOEXEC);
         if ret < 0 { return Err(Error::Open); }
@@ -90,3 +97,132 @@ pub fn openpath_at_cwd(name: &CStr) -> Result<OwnedFd, Error> {
     Ok(unsafe { OwnedFd::from_raw_fd(fd) })
 }
 
+
+pub fn openpath(dir: &Path) -> Result<OwnedFd, Error> {
+    let cstr = CString::new(dir.as_os_str().as_encoded_bytes()).map_err(|_| Error::Open)?;
+    openpath_at_cwd(&cstr)
+}
+
+// openat2 isn't wrapped by glibc so this goes through syscall
+pub fn openat2<Fd: AsRawFd>(fd: &Fd, name: &CStr, flags: libc::c_int, resolve: u64) -> Result<OwnedFd, Error> {
+    let fd = unsafe {
+        // open_how is non_exhaustive in libc so we can't use a struct literal
+        let mut how: libc::open_how = std::mem::zeroed();
+        how.flags = flags as u64;
+        if flags & libc::O_CREAT != 0 {
+            how.mode = 0o666;
+        }
+        how.resolve = resolve;
+        let ret = libc::syscall(
+            libc::SYS_openat2, fd.as_raw_fd(), name.as_ptr(),
+            &how as *const libc::open_how, std::mem::size_of::<libc::open_how>()
+        );
+        if ret < 0 { return Err(Error::Open); }
+        ret as libc::c_int
+    };
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// How unpacking is kept inside the destination dir
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Confinement {
+    /// see chroot, this changes process global state so is only suitable for a one-shot process
+    #[default]
+    Chroot,
+    /// every open is an openat2 with RESOLVE_IN_ROOT relative to the destination (or a dir below
+    /// it), so `../foo` and `/foo` resolve inside it. There is no mkdirat2 so names given to
+    /// mkdir are sanitized instead. Needs no namespaces and touches no process state
+    Openat2,
+}
+
+/// The dir we are unpacking into. Every dir and file created while unpacking goes through this so
+/// the chosen Confinement is applied. Methods taking a dir expect either fd() or a dir opened
+/// with open_dir_at
+pub struct Root {
+    fd: OwnedFd,
+    confinement: Confinement,
+}
+
+// a single path component that can't go anywhere but into dir
+pub(crate) fn check_component(name: &[u8]) -> Result<(), Error> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(Error::UnsafeName);
+    }
+    Ok(())
+}
+
+impl Root {
+    pub fn new(dir: &Path, confinement: Confinement) -> Result<Root, Error> {
+        let fd = match confinement {
+            Confinement::Chroot => {
+                chroot(dir)?;
+                openpath_at_cwd(c".")?
+            },
+            Confinement::Openat2 => openpath(dir)?,
+        };
+        Ok(Root { fd, confinement })
+    }
+
+    pub fn fd(&self) -> &OwnedFd {
+        &self.fd
+    }
+
+    pub fn confinement(&self) -> Confinement {
+        self.confinement
+    }
+
+    /// create name in dir for writing, name can be a path
+    pub fn create_file_at<Fd: AsRawFd>(&self, dir: &Fd, name: &CStr) -> Result<File, Error> {
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC;
+        let fd = match self.confinement {
+            Confinement::Chroot => openfile_at(dir, name, flags)?,
+            Confinement::Openat2 => openat2(dir, name, flags, libc::RESOLVE_IN_ROOT)?,
+        };
+        Ok(File::from(fd))
+    }
+
+    /// mkdir name in dir, name must be a single path component
+    pub fn mkdir_at<Fd: AsRawFd>(&self, dir: &Fd, name: &CStr) -> Result<(), Error> {
+        self.check_mkdir(name)?;
+        mkdirat(dir, name)
+    }
+
+    /// the check mkdir_at does on name, for callers that mkdir some other way (io_uring)
+    pub fn check_mkdir(&self, name: &CStr) -> Result<(), Error> {
+        if self.confinement == Confinement::Openat2 {
+            check_component(name.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// open name in dir as an O_PATH fd to use as the dir of later calls
+    pub fn open_dir_at<Fd: AsRawFd>(&self, dir: &Fd, name: &CStr) -> Result<OwnedFd, Error> {
+        match self.confinement {
+            Confinement::Chroot => openpathat(dir, name),
+            Confinement::Openat2 => openat2(
+                dir, name, libc::O_DIRECTORY | libc::O_PATH | libc::O_CLOEXEC, libc::RESOLVE_IN_ROOT
+            ),
+        }
+    }
+
+    /// mkdir a path relative to the root, all but the last component must already exist
+    pub fn mkdir_path(&self, path: &CStr) -> Result<(), Error> {
+        match self.confinement {
+            Confinement::Chroot => mkdirat(&self.fd, path),
+            Confinement::Openat2 => {
+                let bytes = path.to_bytes();
+                match bytes.iter().rposition(|&b| b == b'/') {
+                    None => self.mkdir_at(&self.fd, path),
+                    Some(i) => {
+                        // neither half can have a zero since they came from a CStr
+                        let name = CString::new(&bytes[i + 1..]).unwrap();
+                        if i == 0 { return self.mkdir_at(&self.fd, &name); }
+                        let parent = CString::new(&bytes[..i]).unwrap();
+                        let parent = self.open_dir_at(&self.fd, &parent)?;
+                        self.mkdir_at(&parent, &name)
+                    }
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use std::ffi::{CStr,CString};
+use std::fs::File;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+use crate::common::{Error,Stats,UnpackOptions};
+use crate::compress::Compressed;
+use crate::header::{map_archive,open_v0,open_v1};
+use crate::open::Root;
+use crate::v0::FdBatch;
+use crate::v1::{Message,Paths,verify_messages};
+
+// most files a worker claims at a time, so neighbours (often in the same dir) stay on one thread
+// without one thread ending up with all the big files
+const CHUNK: usize = 32;
+
+enum Data<'a> {
+    Plain(&'a [u8]),
+    Compressed(Compressed<'a>),
+}
+
+// a file to create, by its path relative to the root
+struct Job<'a> {
+    path: Cow<'a, CStr>,
+    data: Data<'a>,
+}
+
+fn write_job(root: &Root, job: &Job, batch: &mut FdBatch) -> Result<(), Error> {
+    let mut file = root.create_file_at(root.fd(), &job.path)?;
+    match &job.data {
+        Data::Plain(data) => file.write_all(data).map_err(|_| Error::Write)?,
+        Data::Compressed(compressed) => compressed.decompress_to(&mut file)?,
+    }
+    batch.push(file);
+    Ok(())
+}
+
+// Runs the jobs on threads that claim a chunk at a time in archive order. A failing job stops
+// every thread from claiming past it, but jobs before it still run, so the error returned is
+// always the one of the first job that fails (the same one a single thread would hit) and
+// everything before it has been written
+fn run_jobs(root: &Root, jobs: &[Job], opts: &UnpackOptions) -> Result<(), Error> {
+    let threads = match opts.threads {
+        0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        n => n,
+    };
+    // no point in threads that would have nothing to claim
+    let threads = threads.min(jobs.len()).max(1);
+    // small archives still get spread over every thread
+    let chunk = jobs.len().div_ceil(threads).clamp(1, CHUNK);
+    let next = AtomicUsize::new(0);
+    // index of the first job known to have failed
+    let failed = AtomicUsize::new(usize::MAX);
+    let worker = || -> Option<(usize, Error)> {
+        let mut batch = FdBatch::new();
+        loop {
+            let start = next.fetch_add(chunk, Ordering::Relaxed);
+            let end = (start + chunk).min(jobs.len());
+            if start >= end { return None; }
+            for (i, job) in jobs[start..end].iter().enumerate() {
+                let i = start + i;
+                // chunks are claimed in order, so nothing this thread claims later is before it
+                if i > failed.load(Ordering::Relaxed) { return None; }
+                if let Err(e) = write_job(root, job, &mut batch) {
+                    failed.fetch_min(i, Ordering::Relaxed);
+                    return Some((i, e));
+                }
+            }
+        }
+    };
+    let first = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads).map(|_| scope.spawn(worker)).collect();
+        handles.into_iter()
+            .filter_map(|handle| handle.join().unwrap())
+            .min_by_key(|(i, _)| *i)
+    });
+    match first {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Same as unpack_v0 but the files are spread over opts.threads threads (see
+/// UnpackOptions::threads) after the dir table has been created on this one, with every file
+/// created by its full path from the root. Errors are deterministic: it is the error of the first
+/// file in the table that fails, as unpack_v0 would report, and every file before it has been
+/// written. opts.copy_file_range is ignored. See Confinement for how opts.confinement affects the
+/// calling process
+pub fn unpack_v0_parallel(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive(infile, outdir, opts)?;
+    let archive = open_v0(mmap.as_deref().unwrap_or(&[]), opts)?;
+    if opts.verify_only { return Ok(archive.stats()); }
+
+    let root = Root::new(outdir, opts.confinement)?;
+    for name in archive.dirs() {
+        root.mkdir_path(name)?;
+    }
+
+    let jobs: Vec<Job> = archive.files()
+        .map(|(name, data)| Job { path: Cow::Borrowed(name), data: Data::Plain(data) })
+        .collect();
+    run_jobs(&root, &jobs, opts)?;
+    Ok(archive.stats())
+}
+
+/// Same as unpack_v1 but in two passes: the whole archive is decoded first, creating every dir
+/// on this thread and collecting each file with its full path, then the files are spread over
+/// opts.threads threads like unpack_v0_parallel, compressed ones included. So unlike unpack_v1 a
+/// bad message fails the unpack before any file is created. Errors while creating files are
+/// deterministic, see unpack_v0_parallel. See Confinement for how opts.confinement affects the
+/// calling process
+pub fn unpack_v1_parallel(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive(infile, outdir, opts)?;
+    let decoder = open_v1(mmap.as_deref().unwrap_or(&[]), opts)?;
+    if opts.verify_only { return verify_messages(decoder); }
+
+    let root = Root::new(outdir, opts.confinement)?;
+    let mut stats = Stats::default();
+    let mut paths = Paths::default();
+    let mut jobs = vec![];
+    // names from the decoder have no zeros, so neither do paths made of them
+    let path_of = |paths: &mut Paths, name: &CStr| {
+        let path = CString::new(paths.push(name.to_bytes())).unwrap();
+        paths.pop();
+        path
+    };
+    for message in decoder {
+        let message = message?;
+        message.count(&mut stats);
+        match message {
+            Message::File { name, data } => {
+                jobs.push(Job { path: Cow::Owned(path_of(&mut paths, name)), data: Data::Plain(data) });
+            },
+            Message::Compressed { name, file } => {
+                jobs.push(Job { path: Cow::Owned(path_of(&mut paths, name)), data: Data::Compressed(file) });
+            },
+            Message::Dir { name, empty } => {
+                // the decoder refuses names that aren't a single component under any confinement,
+                // which paths (and so the parents of the jobs) rely on
+                root.mkdir_path(&path_of(&mut paths, name))?;
+                if !empty { paths.push(name.to_bytes()); }
+            },
+            Message::Pop => paths.pop(),
+        }
+    }
+
+    run_jobs(&root, &jobs, opts)?;
+    Ok(stats)
+}
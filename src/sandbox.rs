@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io::{Read,Write};
+use std::os::fd::{FromRawFd,OwnedFd};
+use std::panic::{catch_unwind,AssertUnwindSafe};
+
+use crate::common::{Error,Format,Limit,Stats};
+
+// Results come back over the pipe as a status word followed by 4 words:
+//   ok:  0 dirs files data_bytes name_bytes
+//   err: 1 code field field field
+const WIRE_WORDS: usize = 5;
+type Wire = [u64; WIRE_WORDS];
+
+// exhaustive on purpose so adding an Error variant fails to compile until it has a code here and
+// in decode_error. Codes follow the order of the variants in Error: those without an offset count
+// up from 0 and those with one from 100, so a new variant takes the next code of its kind. Parent
+// and child are the same binary, so codes can be renumbered freely
+fn encode_error(e: &Error) -> Wire {
+    let (code, a, b, c) = match *e {
+        Error::Getdents => (0, 0, 0, 0),
+        Error::NoOutfile => (1, 0, 0, 0),
+        Error::CopyFileRange => (2, 0, 0, 0),
+        Error::Align => (3, 0, 0, 0),
+        Error::Open => (4, 0, 0, 0),
+        Error::Write => (5, 0, 0, 0),
+        Error::Read => (6, 0, 0, 0),
+        Error::Statx => (7, 0, 0, 0),
+        Error::Fstat => (8, 0, 0, 0),
+        Error::DirTooDeep => (9, 0, 0, 0),
+        Error::Mkdirat => (10, 0, 0, 0),
+        Error::Ring => (11, 0, 0, 0),
+        Error::Mmap => (12, 0, 0, 0),
+        Error::Chroot => (13, 0, 0, 0),
+        Error::NotADir => (14, 0, 0, 0),
+        Error::FileTooBig => (15, 0, 0, 0),
+        Error::UnsafeName => (16, 0, 0, 0),
+        Error::Child => (17, 0, 0, 0),
+        Error::NotFound => (18, 0, 0, 0),
+        Error::Unsupported => (19, 0, 0, 0),
+        Error::Compress => (20, 0, 0, 0),
+        Error::BadTar => (21, 0, 0, 0),
+        Error::BadMagic => (22, 0, 0, 0),
+        Error::UnknownFormat { format } => (23, format as u64, 0, 0),
+        Error::UnknownVersion { version } => (24, version as u64, 0, 0),
+        Error::UnknownFlags { flags } => (25, flags as u64, 0, 0),
+        Error::WrongFormat { expected, found } => (26, expected as u64, found as u64, 0),
+        Error::BadTag { offset, tag } => (100, offset as u64, tag as u64, 0),
+        Error::UnterminatedName { offset } => (101, offset as u64, 0, 0),
+        Error::NameTooLong { offset } => (102, offset as u64, 0, 0),
+        Error::Truncated { offset } => (103, offset as u64, 0, 0),
+        Error::UnbalancedPop { offset } => (104, offset as u64, 0, 0),
+        Error::MissingPop { offset } => (105, offset as u64, 0, 0),
+        Error::EmptyName { offset } => (106, offset as u64, 0, 0),
+        Error::NameCount { offset, expected, actual } => (107, offset as u64, expected as u64, actual as u64),
+        Error::BadPadding { offset } => (108, offset as u64, 0, 0),
+        Error::DataSize { offset, expected, actual } => (109, offset as u64, expected, actual),
+        Error::LimitExceeded { offset, limit } => (110, offset as u64, limit as u64, 0),
+        Error::Checksum { offset } => (111, offset as u64, 0, 0),
+        Error::TrailingData { offset } => (112, offset as u64, 0, 0),
+        Error::BadIndex { offset } => (113, offset as u64, 0, 0),
+        Error::UnknownCodec { offset, codec } => (114, offset as u64, codec as u64, 0),
+        Error::Decompress { offset } => (115, offset as u64, 0, 0),
+        Error::Unrepresentable { offset } => (116, offset as u64, 0, 0),
+        Error::BadCpio { offset } => (117, offset as u64, 0, 0),
+    };
+    [1, code, a, b, c]
+}
+
+fn decode_error(code: u64, a: u64, b: u64, c: u64) -> Error {
+    match code {
+        0 => Error::Getdents,
+        1 => Error::NoOutfile,
+        2 => Error::CopyFileRange,
+        3 => Error::Align,
+        4 => Error::Open,
+        5 => Error::Write,
+        6 => Error::Read,
+        7 => Error::Statx,
+        8 => Error::Fstat,
+        9 => Error::DirTooDeep,
+        10 => Error::Mkdirat,
+        11 => Error::Ring,
+        12 => Error::Mmap,
+        13 => Error::Chroot,
+        14 => Error::NotADir,
+        15 => Error::FileTooBig,
+        16 => Error::UnsafeName,
+        17 => Error::Child,
+        18 => Error::NotFound,
+        19 => Error::Unsupported,
+        20 => Error::Compress,
+        21 => Error::BadTar,
+        22 => Error::BadMagic,
+        23 => Error::UnknownFormat { format: a as u8 },
+        24 => Error::UnknownVersion { version: a as u8 },
+        25 => Error::UnknownFlags { flags: a as u16 },
+        26 => match (decode_format(a), decode_format(b)) {
+            (Some(expected), Some(found)) => Error::WrongFormat { expected, found },
+            _ => Error::Child,
+        },
+        100 => Error::BadTag { offset: a as usize, tag: b as u8 },
+        101 => Error::UnterminatedName { offset: a as usize },
+        102 => Error::NameTooLong { offset: a as usize },
+        103 => Error::Truncated { offset: a as usize },
+        104 => Error::UnbalancedPop { offset: a as usize },
+        105 => Error::MissingPop { offset: a as usize },
+        106 => Error::EmptyName { offset: a as usize },
+        107 => Error::NameCount { offset: a as usize, expected: b as usize, actual: c as usize },
+        108 => Error::BadPadding { offset: a as usize },
+        109 => Error::DataSize { offset: a as usize, expected: b, actual: c },
+        110 => match decode_limit(b) {
+            Some(limit) => Error::LimitExceeded { offset: a as usize, limit },
+            None => Error::Child,
+        },
+        111 => Error::Checksum { offset: a as usize },
+        112 => Error::TrailingData { offset: a as usize },
+        113 => Error::BadIndex { offset: a as usize },
+        114 => Error::UnknownCodec { offset: a as usize, codec: b as u8 },
+        115 => Error::Decompress { offset: a as usize },
+        116 => Error::Unrepresentable { offset: a as usize },
+        117 => Error::BadCpio { offset: a as usize },
+        _ => Error::Child,
+    }
+}
+
+fn decode_format(x: u64) -> Option<Format> {
+    match x {
+        0 => Some(Format::V0),
+        1 => Some(Format::V1),
+        _ => None,
+    }
+}
+
+fn decode_limit(x: u64) -> Option<Limit> {
+    match x {
+        0 => Some(Limit::TotalBytes),
+        1 => Some(Limit::FileSize),
+        2 => Some(Limit::Files),
+        3 => Some(Limit::Dirs),
+        4 => Some(Limit::NameLen),
+        5 => Some(Limit::Depth),
+        _ => None,
+    }
+}
+
+fn encode(res: &Result<Stats, Error>) -> Wire {
+    match res {
+        Ok(stats) => [0, stats.dirs as u64, stats.files as u64, stats.data_bytes, stats.name_bytes],
+        Err(e) => encode_error(e),
+    }
+}
+
+fn decode(wire: Wire) -> Result<Stats, Error> {
+    match wire {
+        [0, dirs, files, data_bytes, name_bytes] => Ok(Stats {
+            dirs: dirs as usize,
+            files: files as usize,
+            data_bytes,
+            name_bytes,
+        }),
+        [1, code, a, b, c] => Err(decode_error(code, a, b, c)),
+        _ => Err(Error::Child),
+    }
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), Error> {
+    let mut fds = [0; 2];
+    unsafe {
+        let ret = libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC);
+        if ret < 0 { return Err(Error::Child); }
+        Ok((OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])))
+    }
+}
+
+/// Runs f in a forked child and hands back its result, so f can do things like
+/// Confinement::Chroot (which permanently changes the uid and root of the process) without
+/// affecting the caller. This lets a long running process unpack any number of archives, eg
+/// `sandboxed(|| unpack_v1(&infile, outdir, &UnpackOptions::default()))`.
+/// The child is a plain fork, so in a multithreaded caller f should stick to syscalls and
+/// allocation (which glibc makes fork safe) and not take locks other threads might hold. If the
+/// child panics or dies without reporting a result, this returns Error::Child
+pub fn sandboxed<F: FnOnce() -> Result<Stats, Error>>(f: F) -> Result<Stats, Error> {
+    let (rx, tx) = pipe()?;
+    let pid = unsafe { libc::fork() };
+    if pid < 0 { return Err(Error::Child); }
+
+    if pid == 0 {
+        // child: never return into the caller's stack frames, always _exit
+        drop(rx);
+        let res = catch_unwind(AssertUnwindSafe(f)).unwrap_or(Err(Error::Child));
+        let mut bytes = Vec::with_capacity(WIRE_WORDS * 8);
+        for word in encode(&res) {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        let ok = File::from(tx).write_all(&bytes).is_ok();
+        unsafe { libc::_exit(if ok { 0 } else { 1 }); }
+    }
+
+    drop(tx);
+    let mut bytes = Vec::with_capacity(WIRE_WORDS * 8);
+    let read = File::from(rx).read_to_end(&mut bytes);
+    let status = unsafe {
+        let mut status = 0;
+        loop {
+            let ret = libc::waitpid(pid, &mut status, 0);
+            if ret == pid { break; }
+            if ret < 0 && *libc::__errno_location() != libc::EINTR { return Err(Error::Child); }
+        }
+        status
+    };
+    if read.is_err() || !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+        return Err(Error::Child);
+    }
+    if bytes.len() != WIRE_WORDS * 8 { return Err(Error::Child); }
+    let wire: Wire = std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()));
+    decode(wire)
+}
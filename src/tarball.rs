@@ -0,0 +1,131 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::prelude::OsStrExt;
+
+use tar::EntryType;
+
+use crate::common::{Error,Format,PackOptions,Stats};
+use crate::convert::{Converted,OnSkipped,SkippedKind,Tree,skip};
+use crate::header::{Header,verify_trailer};
+use crate::v0::Archive;
+use crate::v1::{Decoder,Message,Paths};
+
+/// Converts a tar archive (ustar, pax or gnu, as read by the tar crate) in buf to a v0 or v1
+/// archive in out. Only regular files and dirs are stored, with their contents borrowed from buf
+/// so usually the mmap of the tarball. Modes, owners and times are dropped since neither format
+/// has them. Symlinks, hardlinks, devices, fifos and sparse files are reported in
+/// Converted::skipped, or fail the conversion with on_skipped
+pub fn from_tar(buf: &[u8], format: Format, out: File, opts: &PackOptions, on_skipped: OnSkipped) -> Result<Converted, Error> {
+    let mut tree = Tree::default();
+    let mut skipped = vec![];
+    let mut archive = tar::Archive::new(buf);
+    for entry in archive.entries().map_err(|_| Error::BadTar)? {
+        let entry = entry.map_err(|_| Error::BadTar)?;
+        let offset = entry.raw_header_position() as usize;
+        let path = entry.path_bytes();
+        let kind = match entry.header().entry_type() {
+            // old tars mark dirs with a trailing / on a regular entry
+            EntryType::Regular | EntryType::Continuous if path.ends_with(b"/") => {
+                tree.insert_dir(&path)?;
+                continue;
+            },
+            EntryType::Regular | EntryType::Continuous => {
+                let start = entry.raw_file_position() as usize;
+                let data = start.checked_add(entry.size() as usize)
+                    .and_then(|end| buf.get(start..end))
+                    .ok_or(Error::BadTar)?;
+                tree.insert_file(&path, data)?;
+                continue;
+            },
+            EntryType::Directory => {
+                tree.insert_dir(&path)?;
+                continue;
+            },
+            // metadata for the whole archive, nothing to store
+            EntryType::XGlobalHeader => continue,
+            EntryType::Symlink => SkippedKind::Symlink,
+            EntryType::Link => SkippedKind::Hardlink,
+            EntryType::Char => SkippedKind::CharDevice,
+            EntryType::Block => SkippedKind::BlockDevice,
+            EntryType::Fifo => SkippedKind::Fifo,
+            EntryType::GNUSparse => SkippedKind::Sparse,
+            _ => SkippedKind::Other,
+        };
+        skip(&mut skipped, on_skipped, &path, kind, offset)?;
+    }
+    let stats = tree.pack(format, out, opts)?;
+    Ok(Converted { stats, skipped })
+}
+
+// tar::Builder with the headers filled in the same way for every entry
+struct TarWriter<W: Write> {
+    builder: tar::Builder<W>,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn append(&mut self, path: &[u8], entry_type: EntryType, data: &[u8]) -> Result<(), Error> {
+        // a long name record would take it as is
+        if path.split(|&b| b == b'/').any(|x| x == b"..") { return Err(Error::UnsafeName); }
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(if entry_type == EntryType::Directory { 0o755 } else { 0o644 });
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        // falls back to a GNU long name record when the path doesn't fit. Not pax, since the tar
+        // crate splits pax records on newlines and names can have them
+        self.builder.append_data(&mut header, OsStr::from_bytes(path), data).map_err(|_| Error::Write)
+    }
+}
+
+/// Converts the v0 or v1 archive in buf to a ustar archive (with GNU long name records for long
+/// paths, which every tar reads) written to out. All checksums are checked on the way, so a bad
+/// archive can leave out with a partial tarball. Files get mode 644, dirs 755, owned by root with
+/// a zero mtime
+pub fn to_tar<W: Write>(buf: &[u8], out: W) -> Result<Stats, Error> {
+    let header = Header::parse(buf)?;
+    verify_trailer(buf, &header)?;
+    let mut writer = TarWriter { builder: tar::Builder::new(out) };
+    let stats = match header.format {
+        Format::V0 => {
+            let archive = Archive::parse_with(buf, &header)?;
+            archive.verify_checksums()?;
+            for name in archive.dirs() {
+                writer.append(name.to_bytes(), EntryType::Directory, &[])?;
+            }
+            for (name, data) in archive.files() {
+                writer.append(name.to_bytes(), EntryType::Regular, data)?;
+            }
+            archive.stats()
+        },
+        Format::V1 => {
+            let mut stats = Stats::default();
+            let mut paths = Paths::default();
+            for message in Decoder::new(buf).with_header(&header) {
+                let message = message?;
+                message.count(&mut stats);
+                match message {
+                    Message::File { name, data } => {
+                        writer.append(paths.push(name.to_bytes()), EntryType::Regular, data)?;
+                        paths.pop();
+                    },
+                    Message::Compressed { name, file } => {
+                        let data = file.to_vec()?;
+                        writer.append(paths.push(name.to_bytes()), EntryType::Regular, &data)?;
+                        paths.pop();
+                    },
+                    Message::Dir { name, empty } => {
+                        writer.append(paths.push(name.to_bytes()), EntryType::Directory, &[])?;
+                        if empty { paths.pop(); }
+                    },
+                    Message::Pop => paths.pop(),
+                }
+            }
+            stats
+        },
+    };
+    writer.builder.into_inner().map_err(|_| Error::Write)?.flush().map_err(|_| Error::Write)?;
+    Ok(stats)
+}
@@ -0,0 +1,561 @@
+use std::collections::HashSet;
+use std::ffi::{CStr,OsString};
+use std::fs::File;
+use std::io::{Read,Write,BufWriter,Seek,SeekFrom};
+use std::io;
+use std::os::fd::{IntoRawFd,RawFd};
+use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
+
+use crate::checksum::{CrcWriter,crc32c,crc32c_file};
+use crate::common::{Error,Format,PackOptions,Stats,UnpackOptions,UnpackLimits,Limiter,align_to_4,copy_file_range_all,file_size_fstat};
+use crate::header::{Header,TRAILER_LEN,read_header,map_archive,open_v0};
+use crate::liblistdir::{Visitor,Walk,list_dir};
+use crate::open::Root;
+use crate::v1::Paths;
+
+// the four u32 counts at the start of the body
+const COUNTS_SIZE: usize = 4 * 4;
+
+// default fd table size is 64, we 3 + 1 open by default but we don't want to go to fd 257 because
+// that would trigger a realloc and then we waste, so this should always be 4 less than a power of
+// 2. Seems like diminishing returns
+const CLOSE_EVERY: usize = 256 - 4;
+
+/// Validated view of a v0 archive (see pack_v0 for the layout). Archive::parse checks every
+/// header invariant up front so unpacking never has to trust the header:
+///   - the name tables and the size table fit inside the archive
+///   - each name table holds exactly num_dirs/num_files non-empty zero terminated names
+///   - the alignment padding before the size table is zero
+///   - the file sizes sum to exactly the number of bytes after data_start (up to the trailer if
+///     there is one)
+///
+/// It doesn't check checksums, see verify_trailer and Archive::verify_checksums
+pub struct Archive<'a> {
+    pub data_start: usize,
+    dirnames_start: usize,
+    filesizes_start: usize,
+    // 4 bytes per file, or 8 with FLAG_FILE_CHECKSUMS
+    entry_size: usize,
+    num_dirs: usize,
+    num_files: usize,
+    dirnames: &'a [u8],
+    filenames: &'a [u8],
+    filesizes: &'a [u8],
+    data: &'a [u8],
+}
+
+fn read_u32_at(buf: &[u8], offset: usize) -> Result<u32, Error> {
+    let bytes = buf.get(offset..offset + 4).ok_or(Error::Truncated { offset })?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn region(buf: &[u8], start: usize, len: usize) -> Result<&[u8], Error> {
+    let end = start.checked_add(len).ok_or(Error::Truncated { offset: start })?;
+    buf.get(start..end).ok_or(Error::Truncated { offset: start })
+}
+
+// names are zero terminated and back to back, so the table has to end in a zero and contain no
+// empty names (two zeros in a row, or a zero at the start)
+fn check_names(names: &[u8], start: usize, expected: usize) -> Result<(), Error> {
+    if let Some(&last) = names.last() {
+        if last != 0 { return Err(Error::UnterminatedName { offset: start + names.len() - 1 }); }
+    }
+    let mut actual = 0;
+    let mut name_start = 0;
+    for (i, &b) in names.iter().enumerate() {
+        if b != 0 { continue; }
+        if i == name_start { return Err(Error::EmptyName { offset: start + i }); }
+        actual += 1;
+        name_start = i + 1;
+    }
+    if actual != expected {
+        return Err(Error::NameCount { offset: start, expected, actual });
+    }
+    Ok(())
+}
+
+fn names_iter(names: &[u8]) -> impl Iterator<Item = &CStr> {
+    // names were checked in check_names so every chunk has a terminator
+    names.split_inclusive(|&b| b == 0).map(|name| CStr::from_bytes_with_nul(name).unwrap())
+}
+
+impl<'a> Archive<'a> {
+    /// buf is the whole archive, starting with the header
+    pub fn parse(buf: &'a [u8]) -> Result<Archive<'a>, Error> {
+        Archive::parse_with(buf, &read_header(buf, Format::V0, false)?)
+    }
+
+    /// buf is a legacy archive without the header
+    pub fn parse_legacy(buf: &'a [u8]) -> Result<Archive<'a>, Error> {
+        Archive::parse_with(buf, &Header::legacy(Format::V0))
+    }
+
+    /// header has already been read from buf (or is Header::legacy). Offsets stay relative to buf
+    /// so errors point into the file
+    pub fn parse_with(buf: &'a [u8], header: &Header) -> Result<Archive<'a>, Error> {
+        let start = header.body_start();
+        let buf = if header.trailer_checksum() {
+            let end = buf.len().checked_sub(TRAILER_LEN).ok_or(Error::Truncated { offset: buf.len() })?;
+            &buf[..end]
+        } else {
+            buf
+        };
+        let entry_size = if header.file_checksums() { 8 } else { 4 };
+        let num_dirs = read_u32_at(buf, start)? as usize;
+        let num_files = read_u32_at(buf, start + 4)? as usize;
+        let dirnames_size = read_u32_at(buf, start + 8)? as usize;
+        let filenames_size = read_u32_at(buf, start + 12)? as usize;
+
+        let dirnames_start = start + COUNTS_SIZE;
+        let dirnames = region(buf, dirnames_start, dirnames_size)?;
+        let filenames_start = dirnames_start + dirnames_size;
+        let filenames = region(buf, filenames_start, filenames_size)?;
+        check_names(dirnames, dirnames_start, num_dirs)?;
+        check_names(filenames, filenames_start, num_files)?;
+
+        let padding_start = filenames_start + filenames_size;
+        let filesizes_start = padding_start.next_multiple_of(4);
+        let padding = region(buf, padding_start, filesizes_start - padding_start)?;
+        if let Some(i) = padding.iter().position(|&b| b != 0) {
+            return Err(Error::BadPadding { offset: padding_start + i });
+        }
+
+        let filesizes_len = num_files.checked_mul(entry_size).ok_or(Error::Truncated { offset: filesizes_start })?;
+        let filesizes = region(buf, filesizes_start, filesizes_len)?;
+        let data_start = filesizes_start + filesizes_len;
+        let data = &buf[data_start..];
+
+        let expected: u64 = filesizes.chunks_exact(entry_size)
+            .map(|x| u32::from_le_bytes(x[..4].try_into().unwrap()) as u64)
+            .sum();
+        let actual = data.len() as u64;
+        if expected != actual {
+            return Err(Error::DataSize { offset: data_start, expected, actual });
+        }
+
+        Ok(Archive { data_start, dirnames_start, filesizes_start, entry_size, num_dirs, num_files, dirnames, filenames, filesizes, data })
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            dirs: self.num_dirs,
+            files: self.num_files,
+            data_bytes: self.data.len() as u64,
+            name_bytes: (self.dirnames.len() + self.filenames.len()) as u64,
+        }
+    }
+
+    /// Checks the whole archive against limits using only the header, offsets in the error are of
+    /// the name that went over. Depth is the number of /'s in a name
+    pub fn check_limits(&self, limits: &UnpackLimits) -> Result<(), Error> {
+        let mut limiter = Limiter::new(limits.clone());
+        let depth = |name: &[u8]| name.iter().filter(|&&b| b == b'/').count();
+        let mut offset = self.dirnames_start;
+        for name in self.dirs() {
+            let name = name.to_bytes();
+            limiter.dir(name, depth(name), offset)?;
+            offset += name.len() + 1;
+        }
+        for (name, size) in names_iter(self.filenames).zip(self.file_sizes()) {
+            let name = name.to_bytes();
+            limiter.file(name, size as u64, depth(name), offset)?;
+            offset += name.len() + 1;
+        }
+        Ok(())
+    }
+
+    /// Checks every file against its checksum, if the archive has them
+    pub fn verify_checksums(&self) -> Result<(), Error> {
+        if self.entry_size != 8 { return Ok(()); }
+        let stored = self.filesizes.chunks_exact(8).map(|x| u32::from_le_bytes(x[4..].try_into().unwrap()));
+        for (i, ((_name, data), expected)) in self.files().zip(stored).enumerate() {
+            if crc32c(data) != expected {
+                return Err(Error::Checksum { offset: self.filesizes_start + i * 8 + 4 });
+            }
+        }
+        Ok(())
+    }
+
+    /// Data of the file stored as path, found from the name and size tables alone so no other
+    /// file's data is touched. Checks that file's checksum if the archive has them
+    pub fn find(&self, path: &[u8]) -> Result<&'a [u8], Error> {
+        let mut start = 0;
+        for (i, (name, size)) in names_iter(self.filenames).zip(self.file_sizes()).enumerate() {
+            if name.to_bytes() != path {
+                start += size;
+                continue;
+            }
+            let data = &self.data[start..start + size];
+            if self.entry_size == 8 {
+                let at = i * 8 + 4;
+                let expected = u32::from_le_bytes(self.filesizes[at..at + 4].try_into().unwrap());
+                if crc32c(data) != expected { return Err(Error::Checksum { offset: self.filesizes_start + at }); }
+            }
+            return Ok(data);
+        }
+        Err(Error::NotFound)
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &'a CStr> {
+        names_iter(self.dirnames)
+    }
+
+    pub fn file_sizes(&self) -> impl Iterator<Item = usize> + 'a {
+        self.filesizes.chunks_exact(self.entry_size).map(|x| u32::from_le_bytes(x[..4].try_into().unwrap()) as usize)
+    }
+
+    /// (name, data) for each file, data is a slice of the archive
+    pub fn files(&self) -> impl Iterator<Item = (&'a CStr, &'a [u8])> {
+        let mut data = self.data;
+        names_iter(self.filenames).zip(self.file_sizes()).map(move |(name, size)| {
+            // sizes were checked to sum to data.len() in parse
+            let (cur, rest) = data.split_at(size);
+            data = rest;
+            (name, cur)
+        })
+    }
+}
+
+fn join_bytes<'a, I: Iterator<Item = &'a [u8]>>(xs: I) -> Vec<u8> {
+    let mut acc: Vec<u8> = vec![];
+    for x in xs {
+        acc.extend_from_slice(x);
+        acc.push(0)
+    }
+    acc
+}
+
+/// v0 archive format, after the header (see header.rs)
+/// num_dirs: u32le
+/// num_files: u32le
+/// dirnames_size: u32le
+/// filenames_size: u32le
+/// <dirnames with null bytes> of length dirnames_size bytes
+/// <filenames with null bytes> of length filenames_size bytes
+/// 0-3 padding bytes to align file_sizes up to 4 byte alignment (in the file, the header is a
+///   multiple of 4 so this is the same as in the body)
+/// <num_files x u32le file sizes> of length num_files * 4 bytes
+///   with FLAG_FILE_CHECKSUMS each size is followed by the u32le CRC32C of the file, so
+///   num_files * 8 bytes
+/// <data>
+/// ---
+/// files are pathnames relative to root and are stored as given, anything that isn't a regular
+/// file is skipped. A file that changes while it is packed is Error::Read. Dirs are only the parents
+/// of files, see pack_v0_dir for packing a whole tree
+pub fn pack_v0<P: AsRef<Path>>(root: &Path, files: &[P], out: File, opts: &PackOptions) -> Result<Stats, Error> {
+    let header = Header::for_pack(Format::V0, opts);
+    let files = {
+        let mut acc: Vec<&Path> = files.iter()
+            .map(|x| x.as_ref())
+            .filter(|x| root.join(x).is_file())
+            .collect();
+        acc.sort();
+        acc
+    };
+    let mut sizes = vec![];
+    let mut size = 0;
+    let dirs = {
+        let mut acc = HashSet::new();
+        let empty = OsString::new();
+        for file in &files {
+            let mut f = File::open(root.join(file)).map_err(|_| Error::Open)?;
+            let file_len = file_size_fstat(&f)?;
+            if file_len > u32::MAX as u64 { return Err(Error::FileTooBig); }
+            // the checksums go before the data so this means reading every file twice
+            let crc = if header.file_checksums() { crc32c_file(&mut f)? } else { 0 };
+            sizes.push((file_len, crc));
+            size += file_len;
+            for parent in file.ancestors().skip(1) {
+                if parent != empty {
+                    acc.insert(parent.to_owned());
+                }
+            }
+        }
+        let mut acc: Vec<_> = acc.drain().collect();
+        acc.sort();
+        acc
+    };
+
+    let dirs: Vec<&[u8]> = dirs.iter().map(|x| x.as_os_str().as_bytes()).collect();
+    let names: Vec<&[u8]> = files.iter().map(|x| x.as_os_str().as_bytes()).collect();
+    let name_bytes = write_archive(out, &header, &dirs, &names, &sizes, &mut |out| {
+        // like DirData, a file that changed since its size and checksum went in the tables would
+        // make the archive silently wrong
+        for (file, &(len, _)) in files.iter().zip(&sizes) {
+            let f = File::open(root.join(file)).map_err(|_| Error::Open)?;
+            let copied = io::copy(&mut (&f).take(len), out).map_err(|_| Error::Write)?;
+            if copied != len || file_size_fstat(&f)? != len { return Err(Error::Read); }
+        }
+        Ok(())
+    })?;
+
+    Ok(Stats {
+        dirs: dirs.len(),
+        files: files.len(),
+        data_bytes: size,
+        name_bytes,
+    })
+}
+
+// First pass of pack_v0_dir: the name tables and sizes, in the order list_dir finds things so
+// every dir comes before what is in it
+struct DirTables {
+    paths: Paths,
+    dirs: Vec<Vec<u8>>,
+    files: Vec<Vec<u8>>,
+    sizes: Vec<(u64, u32)>,
+    checksums: bool,
+}
+
+impl Visitor for DirTables {
+    type Error = Error;
+
+    fn on_file(&mut self, name: &CStr, mut file: File) -> Result<(), Error> {
+        let len = file_size_fstat(&file)?;
+        if len > u32::MAX as u64 { return Err(Error::FileTooBig); }
+        let crc = if self.checksums { crc32c_file(&mut file)? } else { 0 };
+        self.files.push(self.paths.push(name.to_bytes()).to_vec());
+        self.paths.pop();
+        self.sizes.push((len, crc));
+        Ok(())
+    }
+
+    fn on_dir(&mut self, name: &CStr) -> Result<Walk, Error> {
+        self.dirs.push(self.paths.push(name.to_bytes()).to_vec());
+        Ok(Walk::Continue)
+    }
+
+    fn leave_dir(&mut self) -> Result<(), Error> {
+        self.paths.pop();
+        Ok(())
+    }
+}
+
+// Second pass of pack_v0_dir: walks the tree again and copies each file into out. getdents
+// returns the same order for a dir that hasn't changed, anything that doesn't line up with the
+// tables from the first pass means the tree changed under us
+struct DirData<'a> {
+    out: &'a mut dyn Write,
+    paths: Paths,
+    files: &'a [Vec<u8>],
+    sizes: &'a [(u64, u32)],
+    next: usize,
+}
+
+impl Visitor for DirData<'_> {
+    type Error = Error;
+
+    fn on_file(&mut self, name: &CStr, file: File) -> Result<(), Error> {
+        let path = self.paths.push(name.to_bytes());
+        let expected = self.files.get(self.next).ok_or(Error::Read)?;
+        if path != expected.as_slice() { return Err(Error::Read); }
+        self.paths.pop();
+        let len = self.sizes[self.next].0;
+        self.next += 1;
+        let copied = io::copy(&mut (&file).take(len), self.out).map_err(|_| Error::Write)?;
+        if copied != len || file_size_fstat(&file)? != len { return Err(Error::Read); }
+        Ok(())
+    }
+
+    fn on_dir(&mut self, name: &CStr) -> Result<Walk, Error> {
+        self.paths.push(name.to_bytes());
+        Ok(Walk::Continue)
+    }
+
+    fn leave_dir(&mut self) -> Result<(), Error> {
+        self.paths.pop();
+        Ok(())
+    }
+}
+
+/// Packs the tree under indir as a v0 archive. Unlike pack_v0 this takes whatever is in the
+/// tree, names with newlines or that aren't UTF-8 included, and keeps empty dirs. The walk is
+/// list_dir's, so files are opened relative to their dir and sized with fstat, and it is done
+/// twice: once for the tables (and checksums) that go before the data, then again for the data.
+/// Names go in the tables in walk order rather than sorted. Fails with Error::Read if the tree
+/// changes between the two walks. Only regular files and dirs are packed
+pub fn pack_v0_dir(indir: &Path, out: File, opts: &PackOptions) -> Result<Stats, Error> {
+    if !indir.is_dir() { return Err(Error::NotADir); }
+    let header = Header::for_pack(Format::V0, opts);
+    let mut tables = DirTables { paths: Paths::default(), dirs: vec![], files: vec![], sizes: vec![], checksums: header.file_checksums() };
+    list_dir(indir, &mut tables)?;
+
+    let dirs: Vec<&[u8]> = tables.dirs.iter().map(|x| x.as_slice()).collect();
+    let names: Vec<&[u8]> = tables.files.iter().map(|x| x.as_slice()).collect();
+    let name_bytes = write_archive(out, &header, &dirs, &names, &tables.sizes, &mut |out| {
+        let mut data = DirData { out, paths: Paths::default(), files: &tables.files, sizes: &tables.sizes, next: 0 };
+        list_dir(indir, &mut data)?;
+        if data.next != tables.files.len() { return Err(Error::Read); }
+        Ok(())
+    })?;
+
+    Ok(Stats {
+        dirs: dirs.len(),
+        files: names.len(),
+        data_bytes: tables.sizes.iter().map(|(size, _)| size).sum(),
+        name_bytes,
+    })
+}
+
+/// Packs files that are already in memory, eg read out of another archive format, as a v0
+/// archive. Names are full paths and go in the tables in the order given, so dirs should come
+/// before anything inside them
+pub(crate) fn pack_v0_entries(out: File, opts: &PackOptions, dirs: &[&[u8]], files: &[(&[u8], &[u8])]) -> Result<Stats, Error> {
+    let header = Header::for_pack(Format::V0, opts);
+    let mut sizes = Vec::with_capacity(files.len());
+    for (_, data) in files {
+        if data.len() as u64 > u32::MAX as u64 { return Err(Error::FileTooBig); }
+        let crc = if header.file_checksums() { crc32c(data) } else { 0 };
+        sizes.push((data.len() as u64, crc));
+    }
+    let names: Vec<&[u8]> = files.iter().map(|(name, _)| *name).collect();
+    let name_bytes = write_archive(out, &header, dirs, &names, &sizes, &mut |out| {
+        for (_, data) in files {
+            out.write_all(data).map_err(|_| Error::Write)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(Stats {
+        dirs: dirs.len(),
+        files: files.len(),
+        data_bytes: sizes.iter().map(|(size, _)| size).sum(),
+        name_bytes,
+    })
+}
+
+// the whole archive including the trailer, data(out) writes out the contents of every file in
+// the order of files. Returns the size of the name tables
+fn write_archive(out: File, header: &Header, dirs: &[&[u8]], files: &[&[u8]], sizes: &[(u64, u32)], data: &mut dyn FnMut(&mut dyn Write) -> Result<(), Error>) -> Result<u64, Error> {
+    let mut outwriter = BufWriter::new(out);
+    let name_bytes = if header.trailer_checksum() {
+        let mut writer = CrcWriter::new(outwriter);
+        let name_bytes = write_v0(&mut writer, header, dirs, files, sizes, data)?;
+        let crc;
+        (outwriter, crc) = writer.into_parts();
+        outwriter.write_all(&crc.to_le_bytes()).map_err(|_| Error::Write)?;
+        name_bytes
+    } else {
+        write_v0(&mut outwriter, header, dirs, files, sizes, data)?
+    };
+    outwriter.flush().map_err(|_| Error::Write)?;
+    Ok(name_bytes)
+}
+
+// everything but the trailer, returns the size of the name tables
+fn write_v0<W: Write + Seek>(out: &mut W, header: &Header, dirs: &[&[u8]], files: &[&[u8]], sizes: &[(u64, u32)], data: &mut dyn FnMut(&mut dyn Write) -> Result<(), Error>) -> Result<u64, Error> {
+    let filesb = join_bytes(files.iter().copied());
+    let dirsb = join_bytes(dirs.iter().copied());
+    out.write_all(&header.to_bytes()).map_err(|_| Error::Write)?;
+    for i in [dirs.len(), files.len(), dirsb.len(), filesb.len()] {
+        out.write_all(&(i as u32).to_le_bytes()).map_err(|_| Error::Write)?;
+    }
+    out.write_all(&dirsb).map_err(|_| Error::Write)?;
+    out.write_all(&filesb).map_err(|_| Error::Write)?;
+    align_to_4(out)?;
+
+    for &(size, crc) in sizes {
+        out.write_all(&(size as u32).to_le_bytes()).map_err(|_| Error::Write)?;
+        if header.file_checksums() {
+            out.write_all(&crc.to_le_bytes()).map_err(|_| Error::Write)?;
+        }
+    }
+    data(out)?;
+    Ok((dirsb.len() + filesb.len()) as u64)
+}
+
+// Instead of closing every file we write, we leak the fd and close a batch of them at once with
+// close_range. We can't just close everything above some fd since the caller might own some of
+// those, so only close the runs of fds that we know are ours (usually one run)
+pub(crate) struct FdBatch {
+    fds: Vec<RawFd>,
+}
+
+impl FdBatch {
+    pub(crate) fn new() -> FdBatch {
+        FdBatch { fds: Vec::with_capacity(CLOSE_EVERY) }
+    }
+
+    pub(crate) fn push(&mut self, file: File) {
+        self.fds.push(file.into_raw_fd());
+        if self.fds.len() == CLOSE_EVERY {
+            self.close_all();
+        }
+    }
+
+    fn close_all(&mut self) {
+        self.fds.sort_unstable();
+        let mut i = 0;
+        while i < self.fds.len() {
+            let first = self.fds[i];
+            let mut last = first;
+            i += 1;
+            while i < self.fds.len() && self.fds[i] == last + 1 {
+                last += 1;
+                i += 1;
+            }
+            unsafe {
+                libc::close_range(first as u32, last as u32, 0);
+            }
+        }
+        self.fds.clear();
+    }
+}
+
+impl Drop for FdBatch {
+    fn drop(&mut self) {
+        self.close_all();
+    }
+}
+
+/// The data of one file in a v0 archive, as a slice of buf (usually the mmap of the archive). path
+/// is matched exactly against the stored names (as shown by list) and only the header is read to
+/// find it. The trailer checksum isn't checked since that means reading the whole archive, use
+/// verify_only for that
+pub fn extract_v0<'a>(buf: &'a [u8], path: &Path, legacy: bool) -> Result<&'a [u8], Error> {
+    let header = read_header(buf, Format::V0, legacy)?;
+    Archive::parse_with(buf, &header)?.find(path.as_os_str().as_bytes())
+}
+
+/// Unpacks a v0 archive into outdir, which should be empty. The archive is validated, including
+/// any checksums, before anything is created. See Confinement for how opts.confinement affects
+/// the calling process
+pub fn unpack_v0(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive(infile, outdir, opts)?;
+    unpack_v0_buf(infile, mmap.as_deref().unwrap_or(&[]), outdir, opts)
+}
+
+// unpack_v0 of infile already mapped as buf, so unpack can look at the header first
+pub(crate) fn unpack_v0_buf(infile: &File, buf: &[u8], outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let archive = open_v0(buf, opts)?;
+    if opts.verify_only { return Ok(archive.stats()); }
+
+    let root = Root::new(outdir, opts.confinement)?;
+
+    for name in archive.dirs() {
+        root.mkdir_path(name)?;
+    }
+
+    // kinda ugly
+    if opts.copy_file_range {
+        let mut infile = infile;
+        infile.seek(SeekFrom::Start(archive.data_start as u64)).map_err(|_| Error::CopyFileRange)?;
+        for (name, data) in archive.files() {
+            let fileout = root.create_file_at(root.fd(), name)?;
+            // hmm why didn't i use io::copy here originally?
+            copy_file_range_all(infile, &fileout, data.len() as u64)?;
+        };
+
+    } else {
+        let mut batch = FdBatch::new();
+
+        for (name, data) in archive.files() {
+            let mut fileout = root.create_file_at(root.fd(), name)?;
+            fileout.write_all(data).map_err(|_| Error::Write)?;
+            batch.push(fileout);
+        }
+    }
+
+    Ok(archive.stats())
+}
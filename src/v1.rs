@@ -0,0 +1,635 @@
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{Read,Write,BufWriter};
+use std::os::fd::{AsRawFd,OwnedFd};
+use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
+
+use crate::checksum::{Crc32c,crc32c};
+use crate::compress::{Codec,Compressed,compress,worth_compressing};
+use crate::convert::{Node,Tree};
+use crate::common::{Error,Format,PackOptions,Stats,UnpackOptions,UnpackLimits,Limiter,ArchiveFormat1Tag,file_size,sendfile_all};
+use crate::header::{Header,TRAILER_LEN,read_header,map_archive,open_v1};
+use crate::index::{Index,IndexWriter};
+use crate::liblistdir::{Visitor,Walk,list_dir};
+use crate::list::EntryKind;
+use crate::open::{Root,check_component};
+
+// Longest name either decoder takes. v1 names are single path components so this is way more
+// than enough, but the stream decoder needs some bound so a name without a terminator can't make
+// it buffer the whole stream, and the slice decoder has to accept the same archives
+pub(crate) const MAX_NAME_LEN: usize = 4096;
+
+#[derive(Debug)]
+pub enum Message<'a> {
+    File { name: &'a CStr, data: &'a [u8] },
+    // revision 2 only, the data is only decompressed (and checked) by file.decompress_to
+    Compressed { name: &'a CStr, file: Compressed<'a> },
+    // empty is set when the dir was immediately followed by its Pop, which has already been
+    // consumed; this is the fast path for empty dirs so we never have to open them
+    Dir { name: &'a CStr, empty: bool },
+    Pop,
+}
+
+impl Message<'_> {
+    pub fn count(&self, stats: &mut Stats) {
+        match self {
+            Message::File { name, data } => {
+                stats.files += 1;
+                stats.data_bytes += data.len() as u64;
+                stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+            },
+            Message::Compressed { name, file } => {
+                stats.files += 1;
+                stats.data_bytes += file.size;
+                stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+            },
+            Message::Dir { name, .. } => {
+                stats.dirs += 1;
+                stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+            },
+            Message::Pop => {},
+        }
+    }
+}
+
+/// Bounds checked decoder for the v1 format. Every message is validated before it is handed out
+/// so callers never have to index into the archive themselves:
+///   - tag bytes must be one of ArchiveFormat1Tag
+///   - names must have a zero terminator inside the archive, within MAX_NAME_LEN bytes
+///   - names must be a single path component (not empty, . or .. and without a /), else
+///     UnsafeName, so they can only ever name something inside their parent dir
+///   - file lengths must fit in the remaining bytes
+///   - Pop's must balance with Dir's, both during and at the end of the stream
+///   - entries stay within the UnpackLimits given to with_limits, checked before they are handed
+///     out so nothing over a limit is ever written
+///   - with FLAG_FILE_CHECKSUMS, file data matches its checksum (Compressed files can only be
+///     checked as they are decompressed, see compress::Compressed)
+///   - Compressed messages only appear from revision 2 and have a known codec
+///   - when the header says there is an End (Header::has_end) the messages end with it, and
+///     without optional blocks the trailer (if any) comes right after. The trailer checksum itself
+///     is checked by header::verify_trailer, before decoding starts
+///
+/// Errors carry the byte offset of the message (or field) that failed.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    depth: usize,
+    limiter: Limiter,
+    file_checksums: bool,
+    trailer: bool,
+    has_end: bool,
+    optional_blocks: bool,
+    compression: bool,
+    // seen End
+    done: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Decoder<'a> {
+        Decoder::with_limits(buf, UnpackLimits::default())
+    }
+
+    pub fn with_limits(buf: &'a [u8], limits: UnpackLimits) -> Decoder<'a> {
+        Decoder {
+            buf, pos: 0, depth: 0, limiter: Limiter::new(limits),
+            file_checksums: false, trailer: false, has_end: false, optional_blocks: false,
+            compression: false, done: false,
+        }
+    }
+
+    /// Decodes the body described by header, which was read from the start of buf. Without this
+    /// buf is just the messages of a legacy archive. Offsets in errors are relative to buf
+    pub fn with_header(mut self, header: &Header) -> Decoder<'a> {
+        self.pos = header.body_start();
+        self.file_checksums = header.file_checksums();
+        self.trailer = header.trailer_checksum();
+        self.has_end = header.has_end();
+        self.optional_blocks = header.optional_blocks();
+        self.compression = header.compression();
+        self
+    }
+
+    fn take_name(&mut self) -> Result<&'a CStr, Error> {
+        let offset = self.pos;
+        // same as the stream decoder, which only reads up to one byte past the longest name
+        let rest = &self.buf[self.pos..];
+        let window = &rest[..rest.len().min(MAX_NAME_LEN + 1)];
+        let name = CStr::from_bytes_until_nul(window).map_err(|_| {
+            if window.len() > MAX_NAME_LEN { Error::NameTooLong { offset } } else { Error::UnterminatedName { offset } }
+        })?;
+        self.pos += name.to_bytes_with_nul().len();
+        check_component(name.to_bytes())?;
+        Ok(name)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, Error> {
+        let offset = self.pos;
+        let bytes = self.buf.get(self.pos..self.pos + 4).ok_or(Error::Truncated { offset })?;
+        self.pos += 4;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn take_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take_data(1)?[0])
+    }
+
+    fn take_data(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let offset = self.pos;
+        let end = self.pos.checked_add(len).ok_or(Error::Truncated { offset })?;
+        let data = self.buf.get(self.pos..end).ok_or(Error::Truncated { offset })?;
+        self.pos = end;
+        Ok(data)
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<Message<'a>>, Error> {
+        if self.done { return Ok(None); }
+        let offset = self.pos;
+        let tag = match self.buf.get(self.pos) {
+            Some(tag) => tag,
+            None => {
+                if self.depth != 0 { return Err(Error::MissingPop { offset }); }
+                if self.has_end { return Err(Error::Truncated { offset }); }
+                return Ok(None);
+            }
+        };
+        self.pos += 1;
+        match tag.try_into() {
+            Ok(ArchiveFormat1Tag::File) => {
+                let name = self.take_name()?;
+                let len = self.take_u32()? as usize;
+                self.limiter.file(name.to_bytes(), len as u64, self.depth, offset)?;
+                let data = self.take_data(len)?;
+                if self.file_checksums {
+                    let crc_offset = self.pos;
+                    if self.take_u32()? != crc32c(data) { return Err(Error::Checksum { offset: crc_offset }); }
+                }
+                Ok(Some(Message::File { name, data }))
+            },
+            Ok(ArchiveFormat1Tag::Compressed) if self.compression => {
+                let name = self.take_name()?;
+                let codec_offset = self.pos;
+                let codec = self.take_u8()?;
+                let codec = Codec::try_from(codec).map_err(|_| Error::UnknownCodec { offset: codec_offset, codec })?;
+                let size = self.take_u32()? as u64;
+                let len = self.take_u32()? as usize;
+                self.limiter.file(name.to_bytes(), size, self.depth, offset)?;
+                let data_offset = self.pos;
+                let data = self.take_data(len)?;
+                let crc = if self.file_checksums { Some(self.take_u32()?) } else { None };
+                Ok(Some(Message::Compressed { name, file: Compressed { codec, size, data, crc, offset: data_offset } }))
+            },
+            Ok(ArchiveFormat1Tag::Dir) => {
+                let name = self.take_name()?;
+                self.limiter.dir(name.to_bytes(), self.depth, offset)?;
+                if self.buf.get(self.pos) == Some(&(ArchiveFormat1Tag::Pop as u8)) {
+                    self.pos += 1;
+                    Ok(Some(Message::Dir { name, empty: true }))
+                } else {
+                    self.depth += 1;
+                    Ok(Some(Message::Dir { name, empty: false }))
+                }
+            },
+            Ok(ArchiveFormat1Tag::Pop) => {
+                if self.depth == 0 { return Err(Error::UnbalancedPop { offset }); }
+                self.depth -= 1;
+                Ok(Some(Message::Pop))
+            },
+            Ok(ArchiveFormat1Tag::End) if self.has_end => {
+                if self.depth != 0 { return Err(Error::MissingPop { offset }); }
+                // the trailer is checked by verify_trailer and anything before it is optional
+                // blocks, which whoever knows them reads from the end
+                let trailer_len = if self.trailer { TRAILER_LEN } else { 0 };
+                let end = self.buf.len().checked_sub(trailer_len)
+                    .filter(|&end| end >= self.pos)
+                    .ok_or(Error::Truncated { offset: self.pos })?;
+                if !self.optional_blocks && self.pos != end { return Err(Error::TrailingData { offset: self.pos }); }
+                self.done = true;
+                Ok(None)
+            },
+            Ok(ArchiveFormat1Tag::End | ArchiveFormat1Tag::Compressed) | Err(_) => Err(Error::BadTag { offset, tag: *tag }),
+        }
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<Message<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_message().transpose()
+    }
+}
+
+// v1 archive format, after the header (see header.rs)
+// message+
+// message =
+//   | file: <tag> <name zero term> <u32le> <blob>
+//   | dir:  <tag> <name zero term>
+//   | pop:  <tag>
+// from revision 2 (COMPRESSION_VERSION) files can also be
+//   | compressed: <tag> <name zero term> <u8 codec> <u32le size> <u32le stored size> <frame>
+// where frame is a single zstd or lz4 frame that decompresses to size bytes.
+// with FLAG_FILE_CHECKSUMS the file blob (or frame) is followed by the u32le CRC32C of the file
+// contents, and with
+// FLAG_TRAILER_CHECKSUM or FLAG_INDEX the last message is
+//   | end:  <tag>
+// followed by the index, then the trailer
+//
+// alternate format would be to buffer the names and sizes and just dump
+// the blob data so, this avoids the write per message but requires buffering
+// <blob size> <blob data> <message+>
+// message =
+//   | file: <tag> <name zero term> <u32le>
+//   | dir:  <tag> <name zero term>
+//   | pop:  <tag>
+//
+// on the decode side, we'll probably mmap it so not much different
+
+/// Full paths of v1 messages, which only carry their own name. push a File or Dir's name to get
+/// its path, and pop once it is done (right away for files and empty dirs, at its Pop otherwise)
+#[derive(Default)]
+pub(crate) struct Paths {
+    path: Vec<u8>,
+    // length of path before each push
+    lens: Vec<usize>,
+}
+
+impl Paths {
+    pub(crate) fn push(&mut self, name: &[u8]) -> &[u8] {
+        self.lens.push(self.path.len());
+        if !self.path.is_empty() { self.path.push(b'/'); }
+        self.path.extend_from_slice(name);
+        &self.path
+    }
+
+    pub(crate) fn pop(&mut self) {
+        // the decoder balances Pop's so there is always one to pop
+        self.path.truncate(self.lens.pop().unwrap());
+    }
+}
+
+// Generic over any writer that is also an fd so the data can go out with sendfile, which works
+// for files, pipes and sockets; everything else goes through the BufWriter. With checksums the
+// data has to pass through us anyway so it goes through the BufWriter too, and files to compress
+// are read in whole since the compressed size goes before the data
+struct MyVisitor<W: Write + AsRawFd> {
+    writer: BufWriter::<W>,
+    stats: Stats,
+    file_checksums: bool,
+    // running checksum of everything written, with FLAG_TRAILER_CHECKSUM
+    trailer: Option<Crc32c>,
+    has_end: bool,
+    // with FLAG_INDEX, what has been written so far and where
+    index: Option<IndexWriter>,
+    compression: Option<Codec>,
+    paths: Paths,
+    // bytes written
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write + AsRawFd> MyVisitor<W> {
+    fn new(out: W, header: &Header, opts: &PackOptions) -> MyVisitor<W> {
+        MyVisitor {
+            writer: BufWriter::new(out),
+            stats: Stats::default(),
+            file_checksums: header.file_checksums(),
+            trailer: header.trailer_checksum().then(Crc32c::new),
+            has_end: header.has_end(),
+            index: header.index().then(IndexWriter::default),
+            compression: opts.compression.filter(|_| header.compression()),
+            paths: Paths::default(),
+            pos: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(crc) = &mut self.trailer { crc.update(bytes); }
+        self.pos += bytes.len() as u64;
+        self.writer.write_all(bytes).map_err(|_| Error::Write)
+    }
+
+    fn add_to_index(&mut self, name: &CStr, tag: ArchiveFormat1Tag, offset: u64, len: u64) {
+        if let Some(index) = &mut self.index {
+            index.add(self.paths.push(name.to_bytes()), tag, offset, len);
+            self.paths.pop();
+        }
+    }
+
+    // everything of a File message up to the data
+    fn emit_file_header(&mut self, name: &CStr, len: u64) -> Result<(), Error> {
+        self.emit(&[ArchiveFormat1Tag::File as u8])?;
+        self.emit(name.to_bytes_with_nul())?;
+        self.emit(&(len as u32).to_le_bytes())?;
+        self.add_to_index(name, ArchiveFormat1Tag::File, self.pos, len);
+        Ok(())
+    }
+
+    // a file that has been read in to try compressing it, as a Compressed message if that paid off
+    fn emit_compressed(&mut self, name: &CStr, data: &[u8], codec: Codec) -> Result<(), Error> {
+        match compress(codec, data)? {
+            Some(compressed) => {
+                self.emit(&[ArchiveFormat1Tag::Compressed as u8])?;
+                self.emit(name.to_bytes_with_nul())?;
+                // the index points at the codec so readers get the sizes from the message
+                let offset = self.pos;
+                self.emit(&[codec as u8])?;
+                self.emit(&(data.len() as u32).to_le_bytes())?;
+                self.emit(&(compressed.len() as u32).to_le_bytes())?;
+                self.emit(&compressed)?;
+                self.add_to_index(name, ArchiveFormat1Tag::Compressed, offset, self.pos - offset);
+            },
+            None => {
+                self.emit_file_header(name, data.len() as u64)?;
+                self.emit(data)?;
+            },
+        }
+        if self.file_checksums { self.emit(&crc32c(data).to_le_bytes())?; }
+        Ok(())
+    }
+
+    // copies exactly len bytes of file through emit, returns their checksum
+    fn emit_file(&mut self, file: &File, len: u64) -> Result<u32, Error> {
+        if self.buf.is_empty() { self.buf = vec![0u8; 1 << 16]; }
+        let mut buf = std::mem::take(&mut self.buf);
+        let mut crc = Crc32c::new();
+        let mut reader = file.take(len);
+        let mut copied = 0;
+        loop {
+            let n = reader.read(&mut buf).map_err(|_| Error::Read)?;
+            if n == 0 { break; }
+            crc.update(&buf[..n]);
+            self.emit(&buf[..n])?;
+            copied += n as u64;
+        }
+        self.buf = buf;
+        // the file shrank since we got its size
+        if copied != len { return Err(Error::Read); }
+        Ok(crc.value())
+    }
+
+    // End, the index and the trailer, if there are any
+    fn finish(&mut self) -> Result<(), Error> {
+        if !self.has_end { return Ok(()); }
+        self.emit(&[ArchiveFormat1Tag::End as u8])?;
+        if let Some(index) = self.index.take() {
+            self.emit(&index.encode())?;
+        }
+        if self.trailer.is_none() { return Ok(()); }
+        let crc = self.trailer.take().unwrap().value();
+        self.writer.write_all(&crc.to_le_bytes()).map_err(|_| Error::Write)
+    }
+
+    // a file whose contents are already in memory, for the converters
+    fn on_data(&mut self, name: &CStr, data: &[u8]) -> Result<(), Error> {
+        let len = data.len() as u64;
+        if len > u32::MAX as u64 { return Err(Error::FileTooBig); }
+        self.stats.files += 1;
+        self.stats.data_bytes += len;
+        self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+        if let Some(codec) = self.compression.filter(|_| worth_compressing(name.to_bytes(), len)) {
+            return self.emit_compressed(name, data, codec);
+        }
+        self.emit_file_header(name, len)?;
+        self.emit(data)?;
+        if self.file_checksums { self.emit(&crc32c(data).to_le_bytes())?; }
+        Ok(())
+    }
+
+    fn into_writer(mut self) -> Result<W, Error> {
+        // flush all the way through in case W has its own buffer (like Stdout)
+        self.writer.flush().map_err(|_| Error::Write)?;
+        self.writer.into_inner().map_err(|_| Error::Write)
+    }
+}
+
+impl<W: Write + AsRawFd> Visitor for MyVisitor<W> {
+    type Error = Error;
+
+    fn on_file(&mut self, name: &CStr, file: File) -> Result<(), Error> {
+        let len = file_size(&file)?;
+        if len > u32::MAX as u64 { return Err(Error::FileTooBig); }
+        self.stats.files += 1;
+        self.stats.data_bytes += len;
+        self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+
+        if let Some(codec) = self.compression.filter(|_| worth_compressing(name.to_bytes(), len)) {
+            let mut data = Vec::with_capacity(len as usize);
+            (&file).take(len).read_to_end(&mut data).map_err(|_| Error::Read)?;
+            // the file shrank since we got its size
+            if data.len() as u64 != len { return Err(Error::Read); }
+            return self.emit_compressed(name, &data, codec);
+        }
+
+        self.emit_file_header(name, len)?;
+        if self.file_checksums || self.trailer.is_some() {
+            let crc = self.emit_file(&file, len)?;
+            if self.file_checksums { self.emit(&crc.to_le_bytes())?; }
+            return Ok(());
+        }
+        self.writer.flush().map_err(|_| Error::Write)?;
+
+        // TODO io::copy tries a copy_file_range first then falls back to sendfile when the two fds
+        // are on different filesystems; this is happening now b/c I'm going from a disk to tmpfs
+        // but my initial testing was tmpfs -> tmpfs , inside the vm it will be pmem -> tmpfs so
+        // I think we'll want to instead always use sendfile (if we were to go that route, but
+        // write seems good enough)
+        // docs say it is inadvisable to write through get_mut, but ...
+        self.pos += len;
+        let outfile = self.writer.get_mut();
+        // TODO maybe configurable whether to use copy_file_range or sendfile
+        sendfile_all(&file, outfile, len)
+    }
+
+    fn on_dir(&mut self, name: &CStr) -> Result<Walk, Error> {
+        self.stats.dirs += 1;
+        self.stats.name_bytes += name.to_bytes_with_nul().len() as u64;
+        self.emit(&[ArchiveFormat1Tag::Dir as u8])?;
+        self.emit(name.to_bytes_with_nul())?;
+        self.add_to_index(name, ArchiveFormat1Tag::Dir, 0, 0);
+        self.paths.push(name.to_bytes());
+        Ok(Walk::Continue)
+    }
+
+    fn leave_dir(&mut self) -> Result<(), Error> {
+        self.paths.pop();
+        self.emit(&[ArchiveFormat1Tag::Pop as u8])
+    }
+}
+
+// The parent dirs of whatever we're currently unpacking, below the root we unpack into. Both
+// decoders guarantee Pop's are balanced so we never pop past the root
+pub(crate) struct DirStack {
+    root: Root,
+    stack: Vec<OwnedFd>,
+}
+
+impl DirStack {
+    pub(crate) fn new(root: Root) -> DirStack {
+        DirStack { root, stack: Vec::with_capacity(32) }
+    }
+
+    fn parent(&self) -> &OwnedFd {
+        self.stack.last().unwrap_or(self.root.fd())
+    }
+
+    pub(crate) fn create_file(&self, name: &CStr) -> Result<File, Error> {
+        self.root.create_file_at(self.parent(), name)
+    }
+
+    pub(crate) fn mkdir(&mut self, name: &CStr, empty: bool) -> Result<(), Error> {
+        let parent = self.parent();
+        self.root.mkdir_at(parent, name)?;
+        // fast path for empty dir, never open the dir and push it
+        if !empty {
+            let fd = self.root.open_dir_at(parent, name)?;
+            self.stack.push(fd);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.stack.pop();
+    }
+}
+
+/// Packs the tree under indir as a v1 archive into out, which can be a file, pipe or socket. The
+/// index (opts.index) is built as the tree is written and goes out at the end, so this is still one
+/// pass. With opts.compression each file is compressed unless it is tiny or its extension says it
+/// already is, and is stored as is anyway if compressing didn't save at least 1/16th
+pub fn pack_v1<W: Write + AsRawFd>(indir: &Path, out: W, opts: &PackOptions) -> Result<Stats, Error> {
+    if !indir.is_dir() { return Err(Error::NotADir); }
+    let header = Header::for_pack(Format::V1, opts);
+    let mut visitor = MyVisitor::new(out, &header, opts);
+    visitor.emit(&header.to_bytes())?;
+    list_dir(indir, &mut visitor)?;
+    visitor.finish()?;
+    let stats = visitor.stats.clone();
+    let _out = visitor.into_writer()?;
+    Ok(stats)
+}
+
+/// pack_v1 for a tree read out of another format, see convert.rs
+pub(crate) fn pack_v1_tree<W: Write + AsRawFd>(tree: &Tree, out: W, opts: &PackOptions) -> Result<Stats, Error> {
+    fn walk<W: Write + AsRawFd>(tree: &Tree, visitor: &mut MyVisitor<W>) -> Result<(), Error> {
+        for (name, node) in &tree.entries {
+            match node {
+                Node::File(data) => visitor.on_data(name, data)?,
+                Node::Dir(tree) => {
+                    visitor.on_dir(name)?;
+                    walk(tree, visitor)?;
+                    visitor.leave_dir()?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    let header = Header::for_pack(Format::V1, opts);
+    let mut visitor = MyVisitor::new(out, &header, opts);
+    visitor.emit(&header.to_bytes())?;
+    walk(tree, &mut visitor)?;
+    visitor.finish()?;
+    let stats = visitor.stats.clone();
+    let _out = visitor.into_writer()?;
+    Ok(stats)
+}
+
+/// The data of one file in a v1 archive, borrowed from buf (usually the mmap of the archive)
+/// unless it was compressed. path is matched exactly against the full paths (as shown by list).
+/// Archives with an index are looked up in it, others are scanned. Checks the file's checksum if
+/// the archive has them but not the trailer, use verify_only for that
+pub fn extract_v1<'a>(buf: &'a [u8], path: &Path, legacy: bool) -> Result<Cow<'a, [u8]>, Error> {
+    let header = read_header(buf, Format::V1, legacy)?;
+    let path = path.as_os_str().as_bytes();
+    if let Some(index) = Index::parse(buf, &header)? {
+        let entry = index.find(path)?.filter(|entry| entry.kind == EntryKind::File).ok_or(Error::NotFound)?;
+        // entry checked that the data and its checksum are inside buf
+        let start = entry.offset as usize;
+        let end = start + entry.len as usize;
+        let data = &buf[start..end];
+        let crc = header.file_checksums().then(|| u32::from_le_bytes(buf[end..end + 4].try_into().unwrap()));
+        if let Some(codec) = entry.codec {
+            let file = Compressed { codec, size: entry.size, data, crc, offset: start };
+            return Ok(Cow::Owned(file.to_vec()?));
+        }
+        if crc.is_some_and(|crc| crc != crc32c(data)) { return Err(Error::Checksum { offset: end }); }
+        return Ok(Cow::Borrowed(data));
+    }
+
+    let mut paths = Paths::default();
+    for message in Decoder::new(buf).with_header(&header) {
+        match message? {
+            Message::File { name, data } => {
+                if paths.push(name.to_bytes()) == path { return Ok(Cow::Borrowed(data)); }
+                paths.pop();
+            },
+            Message::Compressed { name, file } => {
+                if paths.push(name.to_bytes()) == path { return Ok(Cow::Owned(file.to_vec()?)); }
+                paths.pop();
+            },
+            Message::Dir { name, empty } => {
+                paths.push(name.to_bytes());
+                if empty { paths.pop(); }
+            },
+            Message::Pop => paths.pop(),
+        }
+    }
+    Err(Error::NotFound)
+}
+
+// Decodes (and decompresses) everything without writing, for UnpackOptions::verify_only
+pub(crate) fn verify_messages(decoder: Decoder) -> Result<Stats, Error> {
+    let mut stats = Stats::default();
+    for message in decoder {
+        let message = message?;
+        message.count(&mut stats);
+        if let Message::Compressed { file, .. } = message {
+            file.decompress_to(&mut std::io::sink())?;
+        }
+    }
+    Ok(stats)
+}
+
+/// Unpacks a v1 archive into outdir, which should be empty. A trailer checksum is checked before
+/// anything is created, file checksums as each file is reached. Compressed files are decompressed
+/// straight into their destination. See Confinement for how opts.confinement affects the calling
+/// process
+// TODO these are semi duplicated with stuff in liblistdir
+pub fn unpack_v1(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let mmap = map_archive(infile, outdir, opts)?;
+    unpack_v1_buf(mmap.as_deref().unwrap_or(&[]), outdir, opts)
+}
+
+// unpack_v1 of an archive already mapped as buf, so unpack can look at the header first
+pub(crate) fn unpack_v1_buf(buf: &[u8], outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    let decoder = open_v1(buf, opts)?;
+    if opts.verify_only { return verify_messages(decoder); }
+
+    let mut stats = Stats::default();
+    let mut stack = DirStack::new(Root::new(outdir, opts.confinement)?);
+
+    for message in decoder {
+        let message = message?;
+        message.count(&mut stats);
+        match message {
+            Message::File { name, data } => {
+                let mut file = stack.create_file(name)?;
+                file.write_all(data).map_err(|_| Error::Write)?;
+            },
+            Message::Compressed { name, file: compressed } => {
+                let mut file = stack.create_file(name)?;
+                compressed.decompress_to(&mut file)?;
+            },
+            Message::Dir { name, empty } => {
+                stack.mkdir(name, empty)?;
+            },
+            Message::Pop => {
+                stack.pop();
+            },
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,377 @@
+use std::ffi::CString;
+use std::io::{BufRead,BufReader,Read,Write};
+use std::io;
+use std::path::Path;
+
+use crate::checksum::Crc32c;
+use crate::common::{Error,Format,Stats,UnpackOptions,UnpackLimits,Limiter,ArchiveFormat1Tag};
+use crate::compress::{Codec,decompress};
+use crate::header::{Header,HEADER_LEN,TRAILER_LEN};
+use crate::open::{Root,check_component};
+use crate::v1::{DirStack,MAX_NAME_LEN};
+
+#[derive(Debug)]
+pub enum StreamMessage {
+    // the data is not read yet, call StreamDecoder::copy_data to get it. len is of the contents,
+    // so after decompressing for compressed files
+    File { name: CString, len: u64 },
+    Dir { name: CString, empty: bool },
+    Pop,
+}
+
+impl StreamMessage {
+    pub fn count(&self, stats: &mut Stats) {
+        match self {
+            StreamMessage::File { name, len } => {
+                stats.files += 1;
+                stats.data_bytes += len;
+                stats.name_bytes += name.as_bytes_with_nul().len() as u64;
+            },
+            StreamMessage::Dir { name, .. } => {
+                stats.dirs += 1;
+                stats.name_bytes += name.as_bytes_with_nul().len() as u64;
+            },
+            StreamMessage::Pop => {},
+        }
+    }
+}
+
+/// Same checks as v1::Decoder but reading from a BufRead instead of a slice, so the archive can
+/// come from a pipe or socket. File data is left in the reader and streamed out with copy_data;
+/// if the caller doesn't, next_message skips over it. Since nothing can be checked up front, a
+/// file checksum is checked after its data has been copied out and the trailer checksum when the
+/// End message is reached. Optional blocks after End (like the index) are no use to a stream so
+/// they are read through and only count towards the trailer. Compressed files come out as File
+/// messages and copy_data decompresses them
+pub struct StreamDecoder<R: BufRead> {
+    reader: R,
+    pos: u64,
+    depth: usize,
+    // length of the data of the last File message, until it has been consumed
+    pending: Option<u64>,
+    // codec and decompressed size when that data is compressed
+    pending_codec: Option<(Codec, u64)>,
+    limiter: Limiter,
+    file_checksums: bool,
+    // running checksum of everything read, with FLAG_TRAILER_CHECKSUM until End is reached
+    trailer: Option<Crc32c>,
+    has_end: bool,
+    optional_blocks: bool,
+    compression: bool,
+    // seen End
+    done: bool,
+}
+
+impl<R: BufRead> StreamDecoder<R> {
+    pub fn new(reader: R) -> StreamDecoder<R> {
+        StreamDecoder::with_limits(reader, UnpackLimits::default())
+    }
+
+    pub fn with_limits(reader: R, limits: UnpackLimits) -> StreamDecoder<R> {
+        StreamDecoder {
+            reader, pos: 0, depth: 0, pending: None, pending_codec: None, limiter: Limiter::new(limits),
+            file_checksums: false, trailer: None, has_end: false, optional_blocks: false,
+            compression: false, done: false,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.pos as usize
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        let buf = self.reader.fill_buf().map_err(|_| Error::Read)?;
+        Ok(buf.first().copied())
+    }
+
+    // only called after peek, so the bytes are already buffered
+    fn consume(&mut self, n: usize) {
+        if let Some(crc) = &mut self.trailer {
+            if let Ok(buf) = self.reader.fill_buf() { crc.update(&buf[..n]); }
+        }
+        self.reader.consume(n);
+        self.pos += n as u64;
+    }
+
+    fn hash(&mut self, bytes: &[u8]) {
+        if let Some(crc) = &mut self.trailer { crc.update(bytes); }
+    }
+
+    fn take_name(&mut self) -> Result<CString, Error> {
+        let offset = self.offset();
+        let mut buf = Vec::new();
+        (&mut self.reader).take(MAX_NAME_LEN as u64 + 1).read_until(0, &mut buf).map_err(|_| Error::Read)?;
+        self.pos += buf.len() as u64;
+        self.hash(&buf);
+        match buf.last() {
+            Some(0) => {},
+            _ if buf.len() > MAX_NAME_LEN => return Err(Error::NameTooLong { offset }),
+            _ => return Err(Error::UnterminatedName { offset }),
+        }
+        // read_until stops at the first zero so there can't be an interior one
+        let name = CString::from_vec_with_nul(buf).unwrap();
+        check_component(name.as_bytes())?;
+        Ok(name)
+    }
+
+    fn take_u8(&mut self) -> Result<u8, Error> {
+        let offset = self.offset();
+        let byte = self.peek()?.ok_or(Error::Truncated { offset })?;
+        self.consume(1);
+        Ok(byte)
+    }
+
+    fn take_u32(&mut self) -> Result<u32, Error> {
+        let offset = self.offset();
+        let mut bytes = [0u8; 4];
+        self.reader.read_exact(&mut bytes).map_err(|_| Error::Truncated { offset })?;
+        self.pos += 4;
+        self.hash(&bytes);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// Reads and checks the header, call before the first next_message unless the archive is legacy
+    pub fn read_header(&mut self) -> Result<Header, Error> {
+        let mut bytes = [0u8; HEADER_LEN];
+        // not read_exact since a short archive should be BadMagic or Truncated, not Read
+        let mut len = 0;
+        while len < HEADER_LEN {
+            let n = self.reader.read(&mut bytes[len..]).map_err(|_| Error::Read)?;
+            if n == 0 { break; }
+            len += n;
+        }
+        self.pos += len as u64;
+        let header = Header::parse(&bytes[..len])?;
+        self.file_checksums = header.file_checksums();
+        self.has_end = header.has_end();
+        self.optional_blocks = header.optional_blocks();
+        self.compression = header.compression();
+        if header.trailer_checksum() {
+            let mut crc = Crc32c::new();
+            crc.update(&bytes);
+            self.trailer = Some(crc);
+        }
+        Ok(header)
+    }
+
+    // reads to the end of the input, hashing all but the last keep bytes, and returns how many
+    // bytes were skipped and the kept ones
+    fn skip_to_end(&mut self, keep: usize) -> Result<(u64, Vec<u8>), Error> {
+        let offset = self.offset();
+        let mut kept = Vec::with_capacity(keep);
+        let mut skipped = 0;
+        loop {
+            let buf = self.reader.fill_buf().map_err(|_| Error::Read)?;
+            if buf.is_empty() { break; }
+            let n = buf.len();
+            kept.extend_from_slice(buf);
+            self.reader.consume(n);
+            self.pos += n as u64;
+            if kept.len() > keep {
+                let excess = kept.len() - keep;
+                if let Some(crc) = &mut self.trailer { crc.update(&kept[..excess]); }
+                kept.drain(..excess);
+                skipped += excess as u64;
+            }
+        }
+        if kept.len() != keep { return Err(Error::Truncated { offset }); }
+        Ok((skipped, kept))
+    }
+
+    /// Writes the data of the File message just returned by next_message to out, then checks its
+    /// checksum if the archive has them
+    pub fn copy_data<W: Write>(&mut self, out: &mut W) -> Result<(), Error> {
+        let Some(len) = self.pending.take() else { return Ok(()) };
+        let offset = self.offset();
+        let (copied, crc) = if let Some((codec, size)) = self.pending_codec.take() {
+            self.copy_decompressed(out, len, codec, size)?
+        } else {
+            self.copy_plain(out, len)?
+        };
+        self.pos += copied;
+        if copied != len { return Err(Error::Truncated { offset }); }
+        if self.file_checksums {
+            let crc_offset = self.offset();
+            if self.take_u32()? != crc { return Err(Error::Checksum { offset: crc_offset }); }
+        }
+        Ok(())
+    }
+
+    // copy straight out of the BufReader, checksumming on the way if the archive has checksums.
+    // Returns the bytes copied and their checksum (meaningless without file checksums). Not
+    // io::copy, since that can't tell us whether it was the read or the write that failed, which
+    // matters when the input is a socket
+    fn copy_plain<W: Write>(&mut self, out: &mut W, len: u64) -> Result<(u64, u32), Error> {
+        let mut crc = Crc32c::new();
+        let mut copied = 0;
+        while copied < len {
+            let buf = self.reader.fill_buf().map_err(|_| Error::Read)?;
+            if buf.is_empty() { break; }
+            let n = buf.len().min((len - copied) as usize);
+            if self.file_checksums { crc.update(&buf[..n]); }
+            if let Some(trailer) = &mut self.trailer { trailer.update(&buf[..n]); }
+            out.write_all(&buf[..n]).map_err(|_| Error::Write)?;
+            self.reader.consume(n);
+            copied += n as u64;
+        }
+        Ok((copied, crc.value()))
+    }
+
+    // decompresses the len stored bytes into out, returns how many of them were there and the
+    // checksum of the decompressed data
+    fn copy_decompressed<W: Write>(&mut self, out: &mut W, len: u64, codec: Codec, size: u64) -> Result<(u64, u32), Error> {
+        let offset = self.offset();
+        let mut stored = Stored { reader: &mut self.reader, left: len, trailer: self.trailer.as_mut() };
+        let crc = decompress(codec, &mut stored, size, out, offset);
+        // whatever the decoder didn't read still has to be skipped (and hashed)
+        io::copy(&mut stored, &mut io::sink()).map_err(|_| Error::Read)?;
+        let copied = len - stored.left;
+        // short input is the more useful error, the decoder just sees its frame cut off
+        if copied != len { return Err(Error::Truncated { offset }); }
+        Ok((copied, crc?))
+    }
+
+    pub fn next_message(&mut self) -> Result<Option<StreamMessage>, Error> {
+        if self.done { return Ok(None); }
+        if self.pending.is_some() {
+            self.copy_data(&mut io::sink())?;
+        }
+        let offset = self.offset();
+        let tag = match self.peek()? {
+            Some(tag) => tag,
+            None => {
+                if self.depth != 0 { return Err(Error::MissingPop { offset }); }
+                if self.has_end { return Err(Error::Truncated { offset }); }
+                return Ok(None);
+            }
+        };
+        self.consume(1);
+        match (&tag).try_into() {
+            Ok(ArchiveFormat1Tag::File) => {
+                let name = self.take_name()?;
+                let len = self.take_u32()? as u64;
+                self.limiter.file(name.as_bytes(), len, self.depth, offset)?;
+                self.pending = Some(len);
+                Ok(Some(StreamMessage::File { name, len }))
+            },
+            Ok(ArchiveFormat1Tag::Compressed) if self.compression => {
+                let name = self.take_name()?;
+                let codec_offset = self.offset();
+                let codec = self.take_u8()?;
+                let codec = Codec::try_from(codec).map_err(|_| Error::UnknownCodec { offset: codec_offset, codec })?;
+                let size = self.take_u32()? as u64;
+                let len = self.take_u32()? as u64;
+                self.limiter.file(name.as_bytes(), size, self.depth, offset)?;
+                self.pending = Some(len);
+                self.pending_codec = Some((codec, size));
+                Ok(Some(StreamMessage::File { name, len: size }))
+            },
+            Ok(ArchiveFormat1Tag::Dir) => {
+                let name = self.take_name()?;
+                self.limiter.dir(name.as_bytes(), self.depth, offset)?;
+                if self.peek()? == Some(ArchiveFormat1Tag::Pop as u8) {
+                    self.consume(1);
+                    Ok(Some(StreamMessage::Dir { name, empty: true }))
+                } else {
+                    self.depth += 1;
+                    Ok(Some(StreamMessage::Dir { name, empty: false }))
+                }
+            },
+            Ok(ArchiveFormat1Tag::Pop) => {
+                if self.depth == 0 { return Err(Error::UnbalancedPop { offset }); }
+                self.depth -= 1;
+                Ok(Some(StreamMessage::Pop))
+            },
+            Ok(ArchiveFormat1Tag::End) if self.has_end => {
+                if self.depth != 0 { return Err(Error::MissingPop { offset }); }
+                let rest = self.offset();
+                let trailer_len = if self.trailer.is_some() { TRAILER_LEN } else { 0 };
+                let (skipped, trailer) = self.skip_to_end(trailer_len)?;
+                if !self.optional_blocks && skipped != 0 { return Err(Error::TrailingData { offset: rest }); }
+                if let Some(crc) = self.trailer.take() {
+                    let crc_offset = self.offset() - TRAILER_LEN;
+                    if u32::from_le_bytes(trailer[..].try_into().unwrap()) != crc.value() {
+                        return Err(Error::Checksum { offset: crc_offset });
+                    }
+                }
+                self.done = true;
+                Ok(None)
+            },
+            Ok(ArchiveFormat1Tag::End | ArchiveFormat1Tag::Compressed) | Err(_) => Err(Error::BadTag { offset, tag }),
+        }
+    }
+}
+
+// the stored bytes of a compressed file as a BufRead for the decoder, hashing them for the
+// trailer as they go by
+struct Stored<'a, R: BufRead> {
+    reader: &'a mut R,
+    left: u64,
+    trailer: Option<&'a mut Crc32c>,
+}
+
+impl<R: BufRead> Read for Stored<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Stored<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.left == 0 { return Ok(&[]); }
+        let buf = self.reader.fill_buf()?;
+        let n = buf.len().min(self.left as usize);
+        Ok(&buf[..n])
+    }
+
+    fn consume(&mut self, n: usize) {
+        if let Some(crc) = &mut self.trailer {
+            if let Ok(buf) = self.reader.fill_buf() { crc.update(&buf[..n]); }
+        }
+        self.reader.consume(n);
+        self.left -= n as u64;
+    }
+}
+
+/// Unpacks a v1 archive read sequentially from input (eg stdin or a socket) into outdir, which
+/// should be empty. Checksums can only be checked as the data goes by, so on a mismatch the bad
+/// file (or with the trailer, everything) has already been written; use opts.verify_only first
+/// when that matters. See Confinement for how opts.confinement affects the calling process
+pub fn unpack_v1_stream<R: Read>(input: R, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    if !opts.verify_only && !outdir.is_dir() { return Err(Error::NotADir); }
+
+    let mut stats = Stats::default();
+    let mut decoder = StreamDecoder::with_limits(BufReader::with_capacity(1 << 16, input), opts.limits.clone());
+    if !opts.legacy {
+        decoder.read_header()?.expect(Format::V1)?;
+    }
+    if opts.verify_only {
+        // next_message skips (and checks) the data we don't copy out
+        while let Some(message) = decoder.next_message()? {
+            message.count(&mut stats);
+        }
+        return Ok(stats);
+    }
+
+    let mut stack = DirStack::new(Root::new(outdir, opts.confinement)?);
+    while let Some(message) = decoder.next_message()? {
+        message.count(&mut stats);
+        match message {
+            StreamMessage::File { name, .. } => {
+                let mut file = stack.create_file(&name)?;
+                decoder.copy_data(&mut file)?;
+            },
+            StreamMessage::Dir { name, empty } => {
+                stack.mkdir(&name, empty)?;
+            },
+            StreamMessage::Pop => {
+                stack.pop();
+            },
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,165 @@
+// Hand built archives with names that try to get out of the dir being unpacked into, like the ones
+// make_malicious writes. Everything unpacks with Confinement::Openat2 (chroot would confine the
+// whole test process) into out, a few dirs down in the workdir, and whatever each unpacker makes
+// of a name, the only thing that may change is inside out. v1 names that aren't a single
+// component are refused with UnsafeName, and so are v0 dir names that are just ..
+
+use std::fs::{self,File};
+use std::path::{Path,PathBuf};
+
+use archive_testing::{Confinement,Error,Format,Header,Stats,UnpackOptions,unpack_v0,unpack_v0_parallel,unpack_v0_ring,unpack_v1,unpack_v1_parallel,unpack_v1_ring,unpack_v1_stream};
+
+const NAMES: [&[u8]; 6] = [b"..", b"../x", b"../../x", b"../../../x", b"/archive-testing-hostile", b"a/../../x"];
+
+type Unpacker = fn(&File, &Path, &UnpackOptions) -> Result<Stats, Error>;
+
+fn workdir(test: &str) -> PathBuf {
+    let workdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("hostile").join(test);
+    let _ = fs::remove_dir_all(&workdir);
+    workdir
+}
+
+fn options() -> UnpackOptions {
+    UnpackOptions { confinement: Confinement::Openat2, ..UnpackOptions::default() }
+}
+
+// a v0 archive without checksums, see Archive::parse_with for the layout
+fn v0_archive(dirs: &[&[u8]], files: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let names = |names: &mut dyn Iterator<Item = &[u8]>| {
+        names.flat_map(|name| name.iter().copied().chain([0])).collect::<Vec<u8>>()
+    };
+    let dirnames = names(&mut dirs.iter().copied());
+    let filenames = names(&mut files.iter().map(|(name, _)| *name));
+    let mut out = Header::new(Format::V0).to_bytes().to_vec();
+    for count in [dirs.len(), files.len(), dirnames.len(), filenames.len()] {
+        out.extend_from_slice(&(count as u32).to_le_bytes());
+    }
+    out.extend_from_slice(&dirnames);
+    out.extend_from_slice(&filenames);
+    out.resize(out.len().next_multiple_of(4), 0);
+    for (_, data) in files {
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    }
+    for (_, data) in files {
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+enum Message<'a> {
+    File(&'a [u8], &'a [u8]),
+    Dir(&'a [u8]),
+    Pop,
+}
+
+// a v1 archive without checksums or an End, see the Decoder for the messages
+fn v1_archive(messages: &[Message]) -> Vec<u8> {
+    let mut out = Header::new(Format::V1).to_bytes().to_vec();
+    for message in messages {
+        match message {
+            Message::File(name, data) => {
+                out.push(1);
+                out.extend_from_slice(name);
+                out.push(0);
+                out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                out.extend_from_slice(data);
+            },
+            Message::Dir(name) => {
+                out.push(2);
+                out.extend_from_slice(name);
+                out.push(0);
+            },
+            Message::Pop => out.push(3),
+        }
+    }
+    out
+}
+
+fn listing(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+// Unpacks archive into workdir/d1/d2/out with each unpacker and checks nothing turned up next to
+// out or any of the dirs above it (".." of the root resolves to the root under RESOLVE_IN_ROOT,
+// so three levels is plenty), or at the top of the filesystem. Returns what each unpacker made of it
+fn unpack_hostile<'a>(test: &str, archive: &[u8], unpackers: &[(&'a str, Unpacker)]) -> Vec<(&'a str, Result<Stats, Error>)> {
+    unpackers.iter().map(|(name, unpacker)| {
+        let workdir = workdir(&format!("{test}-{name}"));
+        let outdir = workdir.join("d1/d2/out");
+        fs::create_dir_all(&outdir).unwrap();
+        let path = workdir.join("archive");
+        fs::write(&path, archive).unwrap();
+
+        let res = unpacker(&File::open(&path).unwrap(), &outdir, &options());
+        assert_eq!(listing(&workdir), ["archive", "d1"], "{test} {name}: wrote next to d1, got {res:?}");
+        assert_eq!(listing(&workdir.join("d1")), ["d2"], "{test} {name}: wrote next to d2, got {res:?}");
+        assert_eq!(listing(&workdir.join("d1/d2")), ["out"], "{test} {name}: wrote next to out, got {res:?}");
+        assert!(!Path::new("/archive-testing-hostile").exists(), "{test} {name}: wrote to /, got {res:?}");
+        fs::remove_dir_all(&workdir).unwrap();
+        (*name, res)
+    }).collect()
+}
+
+fn stream(infile: &File, outdir: &Path, opts: &UnpackOptions) -> Result<Stats, Error> {
+    unpack_v1_stream(infile, outdir, opts)
+}
+
+const V0_UNPACKERS: [(&str, Unpacker); 3] = [("v0", unpack_v0), ("v0_ring", unpack_v0_ring), ("v0_parallel", unpack_v0_parallel)];
+const V1_UNPACKERS: [(&str, Unpacker); 4] = [("v1", unpack_v1), ("v1_ring", unpack_v1_ring), ("v1_parallel", unpack_v1_parallel), ("v1_stream", stream)];
+
+#[test]
+fn v0_hostile_file_names() {
+    for (i, name) in NAMES.iter().enumerate() {
+        let archive = v0_archive(&[], &[(name, b"escaped")]);
+        unpack_hostile(&format!("v0_file{i}"), &archive, &V0_UNPACKERS);
+    }
+}
+
+// v0 dir names are whole paths, the last component is made inside whatever the rest resolves to
+#[test]
+fn v0_hostile_dir_names() {
+    for (i, name) in NAMES.iter().enumerate() {
+        let file = [*name, b"/f"].concat();
+        let archive = v0_archive(&[name], &[(&file, b"escaped")]);
+        for (unpacker, res) in unpack_hostile(&format!("v0_dir{i}"), &archive, &V0_UNPACKERS) {
+            if *name == b".." {
+                assert!(matches!(res, Err(Error::UnsafeName)), "v0_dir{i} {unpacker}: expected UnsafeName, got {res:?}");
+            }
+        }
+    }
+}
+
+// v1 names are a single component, the decoders refuse anything else before it is made
+#[test]
+fn v1_hostile_file_names() {
+    for (i, name) in NAMES.iter().enumerate() {
+        let archive = v1_archive(&[Message::File(name, b"escaped")]);
+        for (unpacker, res) in unpack_hostile(&format!("v1_file{i}"), &archive, &V1_UNPACKERS) {
+            assert!(matches!(res, Err(Error::UnsafeName)), "v1_file{i} {unpacker}: expected UnsafeName, got {res:?}");
+        }
+    }
+}
+
+#[test]
+fn v1_hostile_dir_names() {
+    for (i, name) in NAMES.iter().enumerate() {
+        let archive = v1_archive(&[Message::Dir(name), Message::File(b"f", b"escaped"), Message::Pop]);
+        for (unpacker, res) in unpack_hostile(&format!("v1_dir{i}"), &archive, &V1_UNPACKERS) {
+            assert!(matches!(res, Err(Error::UnsafeName)), "v1_dir{i} {unpacker}: expected UnsafeName, got {res:?}");
+        }
+    }
+}
+
+// both decoders stop at the same name length, the stream one because it has to buffer names
+#[test]
+fn v1_name_too_long() {
+    let name = vec![b'n'; 5000];
+    let archive = v1_archive(&[Message::File(&name, b"data")]);
+    for (unpacker, res) in unpack_hostile("v1_long_name", &archive, &V1_UNPACKERS) {
+        assert!(matches!(res, Err(Error::NameTooLong { .. })), "{unpacker}: expected NameTooLong, got {res:?}");
+    }
+}
@@ -0,0 +1,813 @@
+// Round trip property tests: generate random trees, pack them with pack_v0/pack_v0_dir/pack_v1,
+// unpack them with every unpacker and check the result is identical to what was packed. Trees have
+// arbitrary byte names, empty files, empty dirs and nesting up to MAX_DIR_DEPTH.
+//
+// Everything unpacks with Confinement::Openat2 since chroot would confine the whole test process.
+// A failing case prints its workdir, named after the seed, and leaves its files there for poking at.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self,File};
+use std::io::{self,Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path,PathBuf};
+
+use archive_testing::{Codec,Confinement,Error,Format,Entries,Entry,Limit,OnSkipped,PackOptions,RingOptions,SkippedKind,Stats,UnpackLimits,UnpackOptions,extract,from_cpio,from_tar,pack_cpio,to_tar,pack_v0,pack_v0_dir,pack_v1,unpack,unpack_v0,unpack_v0_ring,unpack_v1,unpack_v1_ring,unpack_v1_stream,unpack_v0_parallel,unpack_v1_parallel,ring_support};
+use archive_testing::checksum::crc32c;
+use archive_testing::liblistdir::MAX_DIR_DEPTH;
+
+const CASES: u64 = 48;
+
+// xorshift64*, good enough to make trees and keeps the tests dependency free
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // zero is a fixed point of xorshift
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn percent(&mut self, pct: u64) -> bool {
+        self.below(100) < pct
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    File(Vec<u8>),
+    Dir(Tree),
+}
+
+type Tree = BTreeMap<Vec<u8>, Node>;
+
+// any bytes except / and zero, and not . or ..
+fn random_name(rng: &mut Rng, taken: &Tree) -> Vec<u8> {
+    loop {
+        let len = 1 + rng.below(12) as usize;
+        let name: Vec<u8> = (0..len)
+            .map(|_| loop {
+                let b = 1 + rng.below(255) as u8;
+                if b != b'/' { break b; }
+            })
+            .collect();
+        if name == b"." || name == b".." || taken.contains_key(&name) { continue; }
+        return name;
+    }
+}
+
+// random bytes never compress, so some files are a short random run repeated instead
+fn random_data(rng: &mut Rng) -> Vec<u8> {
+    let len = match rng.below(8) {
+        0 | 1 => 0,
+        2 => rng.below(1 << 17),
+        _ => rng.below(512),
+    };
+    if rng.percent(30) {
+        let run: Vec<u8> = (0..1 + rng.below(16)).map(|_| rng.next() as u8).collect();
+        return run.iter().copied().cycle().take(len as usize).collect();
+    }
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+// spine is how many more levels of dirs to force below this one, so every depth up to
+// MAX_DIR_DEPTH gets hit; the random siblings stay shallow to keep the trees a sane size
+fn random_tree(rng: &mut Rng, depth: usize, spine: usize) -> Tree {
+    let mut tree = Tree::new();
+    let entries = if depth < 3 { rng.below(8) } else { rng.below(3) };
+    for _ in 0..entries {
+        let name = random_name(rng, &tree);
+        let node = if depth < 3 && rng.percent(30) {
+            Node::Dir(random_tree(rng, depth + 1, 0))
+        } else {
+            Node::File(random_data(rng))
+        };
+        tree.insert(name, node);
+    }
+    if spine > 0 {
+        let name = random_name(rng, &tree);
+        tree.insert(name, Node::Dir(random_tree(rng, depth + 1, spine - 1)));
+    }
+    tree
+}
+
+fn write_tree(dir: &Path, tree: &Tree) {
+    for (name, node) in tree {
+        let path = dir.join(OsStr::from_bytes(name));
+        match node {
+            Node::File(data) => fs::write(&path, data).unwrap(),
+            Node::Dir(tree) => {
+                fs::create_dir(&path).unwrap();
+                write_tree(&path, tree);
+            },
+        }
+    }
+}
+
+fn read_tree(dir: &Path) -> Tree {
+    let mut tree = Tree::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let file_type = entry.file_type().unwrap();
+        let node = if file_type.is_dir() {
+            Node::Dir(read_tree(&entry.path()))
+        } else {
+            assert!(file_type.is_file(), "{:?} is not a file or dir", entry.path());
+            Node::File(fs::read(entry.path()).unwrap())
+        };
+        tree.insert(entry.file_name().as_bytes().to_vec(), node);
+    }
+    tree
+}
+
+// v0 only stores dirs as parents of files, so dirs with no files anywhere below them are lost
+fn without_empty_dirs(tree: &Tree) -> Tree {
+    let mut acc = Tree::new();
+    for (name, node) in tree {
+        match node {
+            Node::File(data) => { acc.insert(name.clone(), Node::File(data.clone())); },
+            Node::Dir(tree) => {
+                let tree = without_empty_dirs(tree);
+                if !tree.is_empty() {
+                    acc.insert(name.clone(), Node::Dir(tree));
+                }
+            },
+        }
+    }
+    acc
+}
+
+fn file_paths(tree: &Tree, prefix: &Path, acc: &mut Vec<PathBuf>) {
+    for (name, node) in tree {
+        let path = prefix.join(OsStr::from_bytes(name));
+        match node {
+            Node::File(_) => acc.push(path),
+            Node::Dir(tree) => file_paths(tree, &path, acc),
+        }
+    }
+}
+
+fn options(copy_file_range: bool) -> UnpackOptions {
+    UnpackOptions { copy_file_range, confinement: Confinement::Openat2, ..UnpackOptions::default() }
+}
+
+const CHECKSUMS: PackOptions = PackOptions { checksums: true, index: false, compression: None };
+const INDEX: PackOptions = PackOptions { checksums: false, index: true, compression: None };
+const INDEX_CHECKSUMS: PackOptions = PackOptions { checksums: true, index: true, compression: None };
+const ZSTD: PackOptions = PackOptions { checksums: false, index: false, compression: Some(Codec::Zstd) };
+const LZ4_CHECKSUMS: PackOptions = PackOptions { checksums: true, index: false, compression: Some(Codec::Lz4) };
+const ZSTD_INDEX_CHECKSUMS: PackOptions = PackOptions { checksums: true, index: true, compression: Some(Codec::Zstd) };
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Packer {
+    // pack_v0 from the list of files
+    V0List,
+    // pack_v0_dir, which walks the dir and so keeps empty dirs
+    V0Dir,
+    V1,
+}
+
+impl Packer {
+    // what unpacking what it packed from tree should give back
+    fn expected(self, tree: &Tree) -> Tree {
+        match self {
+            Packer::V0List => without_empty_dirs(tree),
+            Packer::V0Dir | Packer::V1 => tree.clone(),
+        }
+    }
+}
+
+fn pack(packer: Packer, tree: &Tree, srcdir: &Path, archive: &Path, opts: &PackOptions) -> Stats {
+    let out = File::create(archive).unwrap();
+    let res = match packer {
+        Packer::V0List => {
+            let mut files = vec![];
+            file_paths(tree, Path::new(""), &mut files);
+            pack_v0(srcdir, &files, out, opts)
+        },
+        Packer::V0Dir => pack_v0_dir(srcdir, out, opts),
+        Packer::V1 => pack_v1(srcdir, out, opts),
+    };
+    res.unwrap()
+}
+
+fn workdir(test: &str) -> PathBuf {
+    let workdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("roundtrip").join(test);
+    let _ = fs::remove_dir_all(&workdir);
+    workdir
+}
+
+// a fresh workdir with tree written to src, packed to archive and an empty out to unpack to
+fn pack_tree(test: &str, packer: Packer, tree: &Tree, opts: &PackOptions) -> PathBuf {
+    let workdir = workdir(test);
+    let srcdir = workdir.join("src");
+    fs::create_dir_all(&srcdir).unwrap();
+    fs::create_dir(workdir.join("out")).unwrap();
+    write_tree(&srcdir, tree);
+    pack(packer, tree, &srcdir, &workdir.join("archive"), opts);
+    workdir
+}
+
+// Writes each seed's tree to src in a fresh workdir named after test and the seed and hands both
+// to check, removing the workdir afterwards unless check panics
+fn for_each_tree<F: Fn(&Tree, &Path)>(test: &str, check: F) {
+    for seed in 0..CASES {
+        let mut rng = Rng::new(seed);
+        let spine = rng.below(MAX_DIR_DEPTH as u64 + 1) as usize;
+        let tree = random_tree(&mut rng, 0, spine);
+
+        let workdir = workdir(&format!("{test}-{seed}"));
+        let srcdir = workdir.join("src");
+        fs::create_dir_all(&srcdir).unwrap();
+        write_tree(&srcdir, &tree);
+        check(&tree, &workdir);
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+}
+
+fn roundtrip<F: Fn(&File, &Path) -> Result<Stats, Error>>(test: &str, packer: Packer, opts: &PackOptions, unpack: F) {
+    for_each_tree(test, |tree, workdir| {
+        let outdir = workdir.join("out");
+        let archive = workdir.join("archive");
+        fs::create_dir(&outdir).unwrap();
+        let packed = pack(packer, tree, &workdir.join("src"), &archive, opts);
+        let unpacked = unpack(&File::open(&archive).unwrap(), &outdir)
+            .unwrap_or_else(|e| panic!("{workdir:?}: unpack failed: {e}"));
+        assert_eq!(packed, unpacked, "{workdir:?}: pack and unpack stats differ");
+        assert!(read_tree(&outdir) == packer.expected(tree), "{workdir:?}: unpacked tree differs");
+    });
+}
+
+#[test]
+fn v0_unpack_v0_write() {
+    roundtrip("v0_write", Packer::V0List, &PackOptions::default(), |f, out| unpack_v0(f, out, &options(false)));
+}
+
+#[test]
+fn v0_unpack_v0_copy_file_range() {
+    roundtrip("v0_copy_file_range", Packer::V0List, &PackOptions::default(), |f, out| unpack_v0(f, out, &options(true)));
+}
+
+#[test]
+fn v0_unpack() {
+    roundtrip("v0_unpack", Packer::V0List, &PackOptions::default(), |f, out| unpack(f, out, &options(false)));
+}
+
+#[test]
+fn v0_unpack_v0_ring() {
+    roundtrip("v0_ring", Packer::V0List, &PackOptions::default(), |f, out| unpack_v0_ring(f, out, &options(false)));
+}
+
+#[test]
+fn v0_unpack_v0_ring_fixed_buffers() {
+    let ring = RingOptions { fixed_buffers: true, ..Default::default() };
+    if !ring_support(&ring).requested {
+        eprintln!("skipping, the kernel can't register fixed buffers");
+        return;
+    }
+    let opts = UnpackOptions { ring, ..options(false) };
+    roundtrip("v0_ring_fixed", Packer::V0List, &PackOptions::default(), |f, out| unpack_v0_ring(f, out, &opts));
+}
+
+fn threads(threads: usize) -> UnpackOptions {
+    UnpackOptions { threads, ..options(false) }
+}
+
+#[test]
+fn v0_unpack_v0_parallel() {
+    for n in [1, 4] {
+        roundtrip(&format!("v0_parallel{n}"), Packer::V0List, &PackOptions::default(), |f, out| unpack_v0_parallel(f, out, &threads(n)));
+    }
+}
+
+#[test]
+fn v1_unpack_v1() {
+    roundtrip("v1", Packer::V1, &PackOptions::default(), |f, out| unpack_v1(f, out, &options(false)));
+}
+
+#[test]
+fn v1_unpack_v1_ring() {
+    roundtrip("v1_ring", Packer::V1, &PackOptions::default(), |f, out| unpack_v1_ring(f, out, &options(false)));
+}
+
+// a tiny queue depth makes the parser block on slots and outstanding ops all the time
+#[test]
+fn v1_unpack_v1_ring_depth() {
+    for ring_depth in [1, 2, 5] {
+        let opts = UnpackOptions { ring: RingOptions { depth: ring_depth, ..Default::default() }, ..options(false) };
+        roundtrip(&format!("v1_ring_depth{ring_depth}"), Packer::V1, &PackOptions::default(), |f, out| unpack_v1_ring(f, out, &opts));
+    }
+}
+
+// each setup the kernel takes, the rest are skipped
+#[test]
+fn v1_unpack_v1_ring_setup() {
+    let setups = [
+        ("fixed_buffers", RingOptions { fixed_buffers: true, ..Default::default() }),
+        ("sqpoll", RingOptions { sqpoll: true, ..Default::default() }),
+        ("defer_taskrun", RingOptions { single_issuer: true, defer_taskrun: true, ..Default::default() }),
+        ("coop_taskrun", RingOptions { coop_taskrun: true, fixed_buffers: true, depth: 4, ..Default::default() }),
+    ];
+    for (name, ring) in setups {
+        if !ring_support(&ring).requested {
+            eprintln!("skipping {name}, the kernel doesn't take it");
+            continue;
+        }
+        let opts = UnpackOptions { ring, ..options(false) };
+        roundtrip(&format!("v1_ring_{name}"), Packer::V1, &PackOptions::default(), |f, out| unpack_v1_ring(f, out, &opts));
+    }
+}
+
+#[test]
+fn v1_unpack_v1_parallel() {
+    for n in [1, 4] {
+        roundtrip(&format!("v1_parallel{n}"), Packer::V1, &PackOptions::default(), |f, out| unpack_v1_parallel(f, out, &threads(n)));
+    }
+}
+
+#[test]
+fn v1_unpack_v1_stream() {
+    roundtrip("v1_stream", Packer::V1, &PackOptions::default(), |f, out| unpack_v1_stream(f, out, &options(false)));
+}
+
+// hands out its bytes and then fails, like a socket that gets reset mid transfer
+struct FailingReader {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.data.len() { return Err(io::Error::other("connection reset")); }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// the input failing in the middle of a file's data is a read error, not a write error
+#[test]
+fn v1_stream_read_error() {
+    let mut tree = Tree::new();
+    tree.insert(b"a".to_vec(), Node::File(vec![7; 1 << 17]));
+    let workdir = pack_tree("v1_stream_read_error", Packer::V1, &tree, &PackOptions::default());
+
+    let bytes = fs::read(workdir.join("archive")).unwrap();
+    let reader = FailingReader { data: bytes[..bytes.len() / 2].to_vec(), pos: 0 };
+    let res = unpack_v1_stream(reader, &workdir.join("out"), &options(false));
+    assert!(matches!(res, Err(Error::Read)), "expected a read error, got {res:?}");
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+#[test]
+fn v1_unpack() {
+    roundtrip("v1_unpack", Packer::V1, &PackOptions::default(), |f, out| unpack(f, out, &options(false)));
+}
+
+#[test]
+fn v0_checksums_unpack_v0() {
+    roundtrip("v0_checksums", Packer::V0List, &CHECKSUMS, |f, out| unpack_v0(f, out, &options(false)));
+}
+
+#[test]
+fn v1_checksums_unpack_v1() {
+    roundtrip("v1_checksums", Packer::V1, &CHECKSUMS, |f, out| unpack_v1(f, out, &options(false)));
+}
+
+#[test]
+fn v1_checksums_unpack_v1_ring() {
+    roundtrip("v1_checksums_ring", Packer::V1, &CHECKSUMS, |f, out| unpack_v1_ring(f, out, &options(false)));
+}
+
+#[test]
+fn v1_checksums_unpack_v1_stream() {
+    roundtrip("v1_checksums_stream", Packer::V1, &CHECKSUMS, |f, out| unpack_v1_stream(f, out, &options(false)));
+}
+
+#[test]
+fn v1_index_unpack_v1() {
+    roundtrip("v1_index", Packer::V1, &INDEX, |f, out| unpack_v1(f, out, &options(false)));
+}
+
+#[test]
+fn v1_index_unpack_v1_ring() {
+    roundtrip("v1_index_ring", Packer::V1, &INDEX_CHECKSUMS, |f, out| unpack_v1_ring(f, out, &options(false)));
+}
+
+#[test]
+fn v1_index_unpack_v1_stream() {
+    roundtrip("v1_index_stream", Packer::V1, &INDEX_CHECKSUMS, |f, out| unpack_v1_stream(f, out, &options(false)));
+}
+
+#[test]
+fn v1_compressed_unpack_v1() {
+    roundtrip("v1_zstd", Packer::V1, &ZSTD, |f, out| unpack_v1(f, out, &options(false)));
+    roundtrip("v1_lz4_checksums", Packer::V1, &LZ4_CHECKSUMS, |f, out| unpack_v1(f, out, &options(false)));
+}
+
+#[test]
+fn v1_compressed_unpack_v1_stream() {
+    roundtrip("v1_zstd_stream", Packer::V1, &ZSTD, |f, out| unpack_v1_stream(f, out, &options(false)));
+    roundtrip("v1_zstd_index_stream", Packer::V1, &ZSTD_INDEX_CHECKSUMS, |f, out| unpack_v1_stream(f, out, &options(false)));
+}
+
+#[test]
+fn v1_compressed_unpack_v1_parallel() {
+    roundtrip("v1_zstd_parallel", Packer::V1, &ZSTD, |f, out| unpack_v1_parallel(f, out, &threads(4)));
+    roundtrip("v1_lz4_checksums_parallel", Packer::V1, &LZ4_CHECKSUMS, |f, out| unpack_v1_parallel(f, out, &threads(4)));
+}
+
+#[test]
+fn v1_compressed_unpack_v1_ring() {
+    roundtrip("v1_zstd_ring", Packer::V1, &ZSTD, |f, out| unpack_v1_ring(f, out, &options(false)));
+    roundtrip("v1_lz4_checksums_ring", Packer::V1, &LZ4_CHECKSUMS, |f, out| unpack_v1_ring(f, out, &options(false)));
+}
+
+// pack random trees and pull every file back out one at a time
+fn extract_each(test: &str, packer: Packer, opts: &PackOptions) {
+    for_each_tree(test, |tree, workdir| {
+        let srcdir = workdir.join("src");
+        let archive = workdir.join("archive");
+        pack(packer, tree, &srcdir, &archive, opts);
+
+        let buf = fs::read(&archive).unwrap();
+        let mut files = vec![];
+        file_paths(tree, Path::new(""), &mut files);
+        for file in &files {
+            let data = extract(&buf, file)
+                .unwrap_or_else(|e| panic!("{workdir:?}: extracting {file:?} failed: {e}"));
+            assert!(data == fs::read(srcdir.join(file)).unwrap(), "{workdir:?}: {file:?} differs");
+        }
+        let res = extract(&buf, Path::new("not/in/the/archive"));
+        assert!(matches!(res, Err(Error::NotFound)), "{workdir:?}: expected NotFound, got {res:?}");
+    });
+}
+
+#[test]
+fn v0_extract() {
+    extract_each("v0_extract", Packer::V0List, &PackOptions::default());
+    extract_each("v0_extract_checksums", Packer::V0List, &CHECKSUMS);
+}
+
+#[test]
+fn v1_extract() {
+    extract_each("v1_extract", Packer::V1, &PackOptions::default());
+    extract_each("v1_extract_index", Packer::V1, &INDEX);
+    extract_each("v1_extract_index_checksums", Packer::V1, &INDEX_CHECKSUMS);
+    extract_each("v1_extract_lz4_checksums", Packer::V1, &LZ4_CHECKSUMS);
+    extract_each("v1_extract_zstd_index_checksums", Packer::V1, &ZSTD_INDEX_CHECKSUMS);
+}
+
+// listing from the index has the same entries and stats as scanning the messages, just in path
+// order
+#[test]
+fn v1_index_list() {
+    for_each_tree("v1_index_list", |tree, workdir| {
+        let list = |opts: &PackOptions| {
+            let archive = workdir.join(format!("archive-{}-{:?}", opts.index, opts.compression));
+            pack(Packer::V1, tree, &workdir.join("src"), &archive, opts);
+            let buf = fs::read(&archive).unwrap();
+            let mut entries = Entries::new(&buf).unwrap();
+            let listed: Result<Vec<Entry>, Error> = entries.by_ref().collect();
+            (listed.unwrap(), entries.stats().clone())
+        };
+        let (mut scanned, scanned_stats) = list(&PackOptions::default());
+        let (indexed, indexed_stats) = list(&INDEX);
+        let (compressed, compressed_stats) = list(&ZSTD_INDEX_CHECKSUMS);
+        scanned.sort_by(|a, b| a.path.as_os_str().as_bytes().cmp(b.path.as_os_str().as_bytes()));
+        assert_eq!(scanned, indexed, "{workdir:?}: entries differ");
+        assert_eq!(scanned_stats, indexed_stats, "{workdir:?}: stats differ");
+        assert_eq!(scanned, compressed, "{workdir:?}: compressed entries differ");
+        assert_eq!(scanned_stats, compressed_stats, "{workdir:?}: compressed stats differ");
+    });
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+fn checksum_error(e: &Error) -> bool {
+    matches!(e, Error::Checksum { .. })
+}
+
+// a bit flipped inside a zstd frame either breaks the frame or decompresses to the wrong contents
+// and fails the file checksum
+fn checksum_or_decompress_error(e: &Error) -> bool {
+    matches!(e, Error::Checksum { .. } | Error::Decompress { .. })
+}
+
+// Packs a compressible file, flips a bit a little past the first match of needle in the archive
+// and checks the unpack (or verify_only) fails with an error accept takes
+fn corrupted<F: Fn(&File, &Path) -> Result<Stats, Error>>(test: &str, packer: Packer, opts: &PackOptions, needle: &[u8], accept: fn(&Error) -> bool, unpack: F) {
+    let mut tree = Tree::new();
+    tree.insert(b"a".to_vec(), Node::File(b"first".to_vec()));
+    tree.insert(b"b".to_vec(), Node::File(b"compressible contents ".repeat(50)));
+    let workdir = pack_tree(test, packer, &tree, opts);
+
+    let archive = workdir.join("archive");
+    let mut bytes = fs::read(&archive).unwrap();
+    let at = bytes.windows(needle.len()).position(|w| w == needle).unwrap();
+    bytes[at + 8] ^= 0x10;
+    fs::write(&archive, &bytes).unwrap();
+
+    let res = unpack(&File::open(&archive).unwrap(), &workdir.join("out"));
+    assert!(matches!(&res, Err(e) if accept(e)), "{test}: unexpected result {res:?}");
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+fn verify_only() -> UnpackOptions {
+    UnpackOptions { verify_only: true, ..options(false) }
+}
+
+const CONTENTS: &[u8] = b"compressible contents";
+
+#[test]
+fn v0_corrupted() {
+    corrupted("v0_corrupted", Packer::V0List, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v0(f, out, &options(false)));
+    corrupted("v0_corrupted_parallel", Packer::V0List, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v0_parallel(f, out, &threads(4)));
+    corrupted("v0_corrupted_verify", Packer::V0List, &CHECKSUMS, CONTENTS, checksum_error, |f, _| unpack(f, Path::new(""), &verify_only()));
+}
+
+#[test]
+fn v1_corrupted() {
+    corrupted("v1_corrupted", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v1(f, out, &options(false)));
+    corrupted("v1_corrupted_ring", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v1_ring(f, out, &options(false)));
+    corrupted("v1_corrupted_parallel", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v1_parallel(f, out, &threads(4)));
+    corrupted("v1_corrupted_stream", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, out| unpack_v1_stream(f, out, &options(false)));
+    corrupted("v1_corrupted_verify", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, _| unpack(f, Path::new(""), &verify_only()));
+    corrupted("v1_corrupted_stream_verify", Packer::V1, &CHECKSUMS, CONTENTS, checksum_error, |f, _| unpack_v1_stream(f, Path::new(""), &verify_only()));
+}
+
+#[test]
+fn v1_compressed_corrupted() {
+    corrupted("v1_compressed_corrupted", Packer::V1, &ZSTD_INDEX_CHECKSUMS, &ZSTD_MAGIC, checksum_or_decompress_error, |f, out| unpack_v1(f, out, &options(false)));
+    corrupted("v1_compressed_corrupted_stream", Packer::V1, &ZSTD_INDEX_CHECKSUMS, &ZSTD_MAGIC, checksum_or_decompress_error, |f, out| unpack_v1_stream(f, out, &options(false)));
+    corrupted("v1_compressed_corrupted_parallel", Packer::V1, &ZSTD_INDEX_CHECKSUMS, &ZSTD_MAGIC, checksum_or_decompress_error, |f, out| unpack_v1_parallel(f, out, &threads(4)));
+    corrupted("v1_compressed_corrupted_verify", Packer::V1, &ZSTD_INDEX_CHECKSUMS, &ZSTD_MAGIC, checksum_or_decompress_error, |f, _| unpack(f, Path::new(""), &verify_only()));
+    corrupted("v1_compressed_corrupted_stream_verify", Packer::V1, &ZSTD_INDEX_CHECKSUMS, &ZSTD_MAGIC, checksum_or_decompress_error, |f, _| unpack_v1_stream(f, Path::new(""), &verify_only()));
+}
+
+// Two files fail in different ways: one has a dir in its place so its open fails, the other has
+// a corrupted frame. However the threads race, the error is the one of whichever comes first in
+// the archive, and every file before that is written
+#[test]
+fn v1_parallel_first_error() {
+    const ZSTD_CHECKSUMS: PackOptions = PackOptions { checksums: true, index: false, compression: Some(Codec::Zstd) };
+    let mut tree = Tree::new();
+    for i in 0..200 {
+        tree.insert(format!("f{i:03}").into_bytes(), Node::File(format!("contents of {i} ").repeat(50).into_bytes()));
+    }
+    let workdir = pack_tree("v1_parallel_first_error", Packer::V1, &tree, &ZSTD_CHECKSUMS);
+    let archive = workdir.join("archive");
+
+    let bytes = fs::read(&archive).unwrap();
+    // every file is compressed, so the frames are in the same order as the files
+    let order: Vec<PathBuf> = Entries::new(&bytes).unwrap().map(|entry| entry.unwrap().path).collect();
+    let frames: Vec<usize> = bytes.windows(4).enumerate().filter(|(_, w)| *w == ZSTD_MAGIC).map(|(at, _)| at).collect();
+    assert_eq!(frames.len(), order.len());
+
+    let (first, second) = (20, 150);
+    for corrupt_first in [true, false] {
+        let (corrupt, blocked) = if corrupt_first { (first, second) } else { (second, first) };
+        let mut corrupted = bytes.clone();
+        corrupted[frames[corrupt] + 8] ^= 0x10;
+        // fix up the trailer so only the file checksum catches it, when that file is unpacked
+        let trailer = corrupted.len() - 4;
+        let crc = crc32c(&corrupted[..trailer]);
+        corrupted[trailer..].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&archive, &corrupted).unwrap();
+        for n in [2, 4, 16] {
+            let outdir = workdir.join(format!("out-{corrupt_first}-{n}"));
+            fs::create_dir(&outdir).unwrap();
+            fs::create_dir(outdir.join(&order[blocked])).unwrap();
+            let res = unpack_v1_parallel(&File::open(&archive).unwrap(), &outdir, &threads(n));
+            if corrupt_first {
+                assert!(matches!(&res, Err(e) if checksum_or_decompress_error(e)), "{n} threads: expected a checksum or decompress error, got {res:?}");
+            } else {
+                assert!(matches!(res, Err(Error::Open)), "{n} threads: expected an open error, got {res:?}");
+            }
+            for path in &order[..first] {
+                let Some(Node::File(data)) = tree.get(path.as_os_str().as_bytes()) else { unreachable!() };
+                assert!(fs::read(outdir.join(path)).unwrap() == *data, "{n} threads: {path:?} wasn't written");
+            }
+        }
+    }
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+type Unpacker = fn(&File, &Path, &UnpackOptions) -> Result<Stats, Error>;
+
+// Each limit set just below what a tiny archive needs, where the first entry is the one over it,
+// so every unpacker has to refuse before writing anything
+#[test]
+fn limits_exceeded() {
+    let file = |name: &[u8], len| Tree::from([(name.to_vec(), Node::File(vec![b'x'; len]))]);
+    let nested = || Tree::from([(b"d".to_vec(), Node::Dir(file(b"f", 1)))]);
+    let cases = [
+        (Limit::NameLen, UnpackLimits { max_name_len: 8, ..Default::default() }, file(b"longer than eight", 1)),
+        (Limit::Depth, UnpackLimits { max_depth: 0, ..Default::default() }, nested()),
+        (Limit::FileSize, UnpackLimits { max_file_size: 10, ..Default::default() }, file(b"f", 100)),
+        (Limit::Files, UnpackLimits { max_files: 0, ..Default::default() }, file(b"f", 1)),
+        (Limit::Dirs, UnpackLimits { max_dirs: 0, ..Default::default() }, nested()),
+        (Limit::TotalBytes, UnpackLimits { max_total_bytes: 10, ..Default::default() }, file(b"f", 100)),
+    ];
+    let unpackers: [(&str, Packer, Unpacker); 6] = [
+        ("v0", Packer::V0Dir, unpack_v0),
+        ("v0_ring", Packer::V0Dir, unpack_v0_ring),
+        ("v0_parallel", Packer::V0Dir, unpack_v0_parallel),
+        ("v1", Packer::V1, unpack_v1),
+        ("v1_ring", Packer::V1, unpack_v1_ring),
+        ("v1_parallel", Packer::V1, unpack_v1_parallel),
+    ];
+    for (limit, limits, tree) in cases {
+        let opts = UnpackOptions { limits, ..threads(4) };
+        for (name, packer, unpacker) in unpackers {
+            let test = format!("limits_{limit:?}_{name}");
+            let workdir = pack_tree(&test, packer, &tree, &PackOptions::default());
+            let archive = workdir.join("archive");
+            let outdir = workdir.join("out");
+            let res = unpacker(&File::open(&archive).unwrap(), &outdir, &opts);
+            assert!(matches!(res, Err(Error::LimitExceeded { limit: l, .. }) if l == limit), "{test}: expected a {limit:?} limit error, got {res:?}");
+            check_untouched(&test, limit, &outdir);
+            if packer == Packer::V1 {
+                let outdir = workdir.join("stream-out");
+                fs::create_dir(&outdir).unwrap();
+                let res = unpack_v1_stream(File::open(&archive).unwrap(), &outdir, &opts);
+                assert!(matches!(res, Err(Error::LimitExceeded { limit: l, .. }) if l == limit), "{test}: expected a {limit:?} limit error from unpack_v1_stream, got {res:?}");
+                check_untouched(&test, limit, &outdir);
+            }
+            fs::remove_dir_all(&workdir).unwrap();
+        }
+    }
+}
+
+// v1 creates each dir as it is decoded, so the dir holding an entry that is too deep can already
+// be there, but nothing else
+fn check_untouched(test: &str, limit: Limit, outdir: &Path) {
+    let left = read_tree(outdir);
+    assert!(left.is_empty() || limit == Limit::Depth && without_empty_dirs(&left).is_empty(), "{test}: wrote {left:?}");
+}
+
+// packing the directory keeps empty dirs, which the file list of pack_v0 can't name
+#[test]
+fn v0_dir_unpack_v0() {
+    roundtrip("v0_dir", Packer::V0Dir, &CHECKSUMS, |f, out| unpack_v0(f, out, &options(false)));
+}
+
+// empty dirs in the table, with a depth small enough to block
+#[test]
+fn v0_dir_unpack_v0_ring() {
+    let opts = UnpackOptions { ring: RingOptions { depth: 3, ..Default::default() }, ..options(false) };
+    roundtrip("v0_dir_ring", Packer::V0Dir, &CHECKSUMS, |f, out| unpack_v0_ring(f, out, &opts));
+}
+
+// v1 -> tar -> v0 and v1 keeps every file and dir
+#[test]
+fn tar_roundtrip() {
+    for_each_tree("tar_roundtrip", |tree, workdir| {
+        let archive = workdir.join("archive");
+        let packed = pack(Packer::V1, tree, &workdir.join("src"), &archive, &ZSTD_INDEX_CHECKSUMS);
+
+        let mut tarball = vec![];
+        let stats = to_tar(&fs::read(&archive).unwrap(), &mut tarball).unwrap();
+        assert_eq!(packed, stats, "{workdir:?}: to_tar stats differ");
+        for format in [Format::V0, Format::V1] {
+            let converted = workdir.join(format!("{format:?}"));
+            let outdir = workdir.join(format!("{format:?}-out"));
+            fs::create_dir(&outdir).unwrap();
+            let res = from_tar(&tarball, format, File::create(&converted).unwrap(), &CHECKSUMS, OnSkipped::Fail)
+                .unwrap_or_else(|e| panic!("{workdir:?}: from_tar {format:?} failed: {e}"));
+            assert!(res.skipped.is_empty());
+            unpack(&File::open(&converted).unwrap(), &outdir, &options(false)).unwrap();
+            assert!(read_tree(&outdir) == *tree, "{workdir:?}: {format:?} tree differs");
+        }
+    });
+}
+
+// entries that aren't regular files or dirs are reported, or fail the conversion
+#[test]
+fn tar_skipped() {
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_ustar();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header.clone(), "dir/file", &b"hello"[..]).unwrap();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header.clone(), "dir/link", "file").unwrap();
+    header.set_entry_type(tar::EntryType::Fifo);
+    builder.append_data(&mut header, "fifo", &[][..]).unwrap();
+    let tarball = builder.into_inner().unwrap();
+
+    let workdir = workdir("tar_skipped");
+    fs::create_dir_all(&workdir).unwrap();
+    let archive = workdir.join("archive");
+    let converted = from_tar(&tarball, Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Report).unwrap();
+    let skipped: Vec<_> = converted.skipped.iter().map(|x| (x.path.to_str().unwrap(), x.kind)).collect();
+    assert_eq!(skipped, [("dir/link", SkippedKind::Symlink), ("fifo", SkippedKind::Fifo)]);
+    assert_eq!((converted.stats.dirs, converted.stats.files), (1, 1));
+    assert_eq!(&extract(&fs::read(&archive).unwrap(), Path::new("dir/file")).unwrap()[..], b"hello");
+
+    let res = from_tar(&tarball, Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Fail);
+    assert!(matches!(res, Err(Error::Unrepresentable { .. })), "expected Unrepresentable, got {res:?}");
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+// pack_cpio -> v0 and v1 keeps every file and dir
+#[test]
+fn cpio_roundtrip() {
+    for_each_tree("cpio_roundtrip", |tree, workdir| {
+        let mut cpio = vec![];
+        let packed = pack_cpio(&workdir.join("src"), &mut cpio).unwrap();
+        for format in [Format::V0, Format::V1] {
+            let converted = workdir.join(format!("{format:?}"));
+            let outdir = workdir.join(format!("{format:?}-out"));
+            fs::create_dir(&outdir).unwrap();
+            let res = from_cpio(&cpio, format, File::create(&converted).unwrap(), &INDEX_CHECKSUMS, OnSkipped::Fail)
+                .unwrap_or_else(|e| panic!("{workdir:?}: from_cpio {format:?} failed: {e}"));
+            assert_eq!((res.stats.dirs, res.stats.files, res.stats.data_bytes), (packed.dirs, packed.files, packed.data_bytes));
+            unpack(&File::open(&converted).unwrap(), &outdir, &options(false)).unwrap();
+            assert!(read_tree(&outdir) == *tree, "{workdir:?}: {format:?} tree differs");
+        }
+    });
+}
+
+fn newc_entry(out: &mut Vec<u8>, name: &str, mode: u32, nlink: u32, data: &[u8]) {
+    let fields = [1, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    out.extend_from_slice(b"070701");
+    for field in fields {
+        out.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.resize(out.len().next_multiple_of(4), 0);
+    out.extend_from_slice(data);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+#[test]
+fn cpio_skipped() {
+    let mut cpio = vec![];
+    newc_entry(&mut cpio, ".", 0o040755, 2, b"");
+    newc_entry(&mut cpio, "dir", 0o040755, 2, b"");
+    newc_entry(&mut cpio, "dir/file", 0o100644, 1, b"hello");
+    newc_entry(&mut cpio, "dir/link", 0o120777, 1, b"file");
+    newc_entry(&mut cpio, "linked", 0o100644, 2, b"");
+    newc_entry(&mut cpio, "TRAILER!!!", 0, 1, b"");
+
+    let workdir = workdir("cpio_skipped");
+    fs::create_dir_all(&workdir).unwrap();
+    let archive = workdir.join("archive");
+    let converted = from_cpio(&cpio, Format::V0, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Report).unwrap();
+    let skipped: Vec<_> = converted.skipped.iter().map(|x| (x.path.to_str().unwrap(), x.kind)).collect();
+    assert_eq!(skipped, [("dir/link", SkippedKind::Symlink), ("linked", SkippedKind::Hardlink)]);
+    assert_eq!((converted.stats.dirs, converted.stats.files), (1, 1));
+    assert_eq!(&extract(&fs::read(&archive).unwrap(), Path::new("dir/file")).unwrap()[..], b"hello");
+
+    let res = from_cpio(&cpio, Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Fail);
+    assert!(matches!(res, Err(Error::Unrepresentable { .. })), "expected Unrepresentable, got {res:?}");
+    // cut off in the middle of the data
+    let res = from_cpio(&cpio[..cpio.len() - 140], Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Report);
+    assert!(matches!(res, Err(Error::BadCpio { .. })), "expected BadCpio, got {res:?}");
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+// the 070702 variant, where the check field of a regular file is the sum of its data bytes
+fn crc_entry(out: &mut Vec<u8>, name: &str, mode: u32, data: &[u8], check: u32) {
+    let start = out.len();
+    newc_entry(out, name, mode, 1, data);
+    out[start..start + 6].copy_from_slice(b"070702");
+    out[start + 102..start + 110].copy_from_slice(format!("{check:08x}").as_bytes());
+}
+
+#[test]
+fn cpio_crc() {
+    let sum = b"hello".iter().map(|&b| b as u32).sum();
+    let cpio = |check| {
+        let mut cpio = vec![];
+        crc_entry(&mut cpio, "dir", 0o040755, b"", 0);
+        crc_entry(&mut cpio, "dir/file", 0o100644, b"hello", check);
+        crc_entry(&mut cpio, "TRAILER!!!", 0, b"", 0);
+        cpio
+    };
+
+    let workdir = workdir("cpio_crc");
+    fs::create_dir_all(&workdir).unwrap();
+    let archive = workdir.join("archive");
+    from_cpio(&cpio(sum), Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Fail).unwrap();
+    assert_eq!(&extract(&fs::read(&archive).unwrap(), Path::new("dir/file")).unwrap()[..], b"hello");
+
+    let res = from_cpio(&cpio(sum + 1), Format::V1, File::create(&archive).unwrap(), &PackOptions::default(), OnSkipped::Fail);
+    assert!(matches!(res, Err(Error::BadCpio { .. })), "expected BadCpio, got {res:?}");
+    fs::remove_dir_all(&workdir).unwrap();
+}
@@ -0,0 +1,79 @@
+// sandboxed runs its closure in a forked child, so these check what comes back over the pipe and
+// that a Confinement::Chroot unpack in the child leaves the test process alone. That one needs
+// unprivileged user namespaces and is skipped where they are turned off
+
+use std::fs::{self,File};
+use std::path::{Path,PathBuf};
+
+use archive_testing::{Error,Format,Limit,PackOptions,Stats,UnpackOptions,pack_v1,sandboxed,unpack_v1};
+
+fn workdir(test: &str) -> PathBuf {
+    let workdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("sandbox").join(test);
+    let _ = fs::remove_dir_all(&workdir);
+    workdir
+}
+
+// unshare(CLONE_NEWUSER) is EPERM where a sysctl or seccomp policy turns off unprivileged user
+// namespaces, and ENOSPC with user.max_user_namespaces at 0. Tried in a child since the test
+// process has threads, which makes it EINVAL
+fn user_namespaces_allowed() -> bool {
+    let res = sandboxed(|| {
+        if unsafe { libc::unshare(libc::CLONE_NEWUSER) } == 0 { return Ok(Stats::default()); }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EPERM | libc::ENOSPC) => Err(Error::Chroot),
+            _ => Ok(Stats::default()),
+        }
+    });
+    !matches!(res, Err(Error::Chroot))
+}
+
+#[test]
+fn chroot_unpack_leaves_parent_alone() {
+    if !user_namespaces_allowed() {
+        eprintln!("skipping chroot_unpack_leaves_parent_alone, unprivileged user namespaces are turned off");
+        return;
+    }
+    let workdir = workdir("chroot_unpack");
+    let srcdir = workdir.join("src");
+    let outdir = workdir.join("out");
+    let archive = workdir.join("archive");
+    fs::create_dir_all(srcdir.join("dir")).unwrap();
+    fs::create_dir(&outdir).unwrap();
+    fs::write(srcdir.join("a"), b"hello").unwrap();
+    fs::write(srcdir.join("dir/b"), b"world").unwrap();
+    let packed = pack_v1(&srcdir, File::create(&archive).unwrap(), &PackOptions::default()).unwrap();
+
+    let cwd = std::env::current_dir().unwrap();
+    let euid = unsafe { libc::geteuid() };
+    // the default confinement is Chroot, which unshares and chroots whoever runs it
+    let infile = File::open(&archive).unwrap();
+    let res = sandboxed(|| unpack_v1(&infile, &outdir, &UnpackOptions::default()));
+    let unpacked = res.unwrap_or_else(|e| panic!("unpack in the child failed: {e}"));
+    assert_eq!(packed, unpacked, "stats differ after the trip through the pipe");
+    assert_eq!(fs::read(outdir.join("a")).unwrap(), b"hello");
+    assert_eq!(fs::read(outdir.join("dir/b")).unwrap(), b"world");
+
+    assert_eq!(std::env::current_dir().unwrap(), cwd, "cwd of the parent changed");
+    assert_eq!(unsafe { libc::geteuid() }, euid, "euid of the parent changed");
+    // still the old root, where the archive is still at its full path
+    assert!(archive.exists(), "root of the parent changed");
+    fs::remove_dir_all(&workdir).unwrap();
+}
+
+#[test]
+fn panic_is_child_error() {
+    let res = sandboxed(|| panic!("the child panics on purpose"));
+    assert!(matches!(res, Err(Error::Child)), "expected Child, got {res:?}");
+}
+
+#[test]
+fn errors_come_back_intact() {
+    let res = sandboxed(|| Err(Error::DataSize { offset: 12, expected: 1 << 40, actual: 3 }));
+    assert!(matches!(res, Err(Error::DataSize { offset: 12, expected: 0x100_0000_0000, actual: 3 })), "got {res:?}");
+    let res = sandboxed(|| Err(Error::LimitExceeded { offset: 99, limit: Limit::Depth }));
+    assert!(matches!(res, Err(Error::LimitExceeded { offset: 99, limit: Limit::Depth })), "got {res:?}");
+    let res = sandboxed(|| Err(Error::WrongFormat { expected: Format::V1, found: Format::V0 }));
+    assert!(matches!(res, Err(Error::WrongFormat { expected: Format::V1, found: Format::V0 })), "got {res:?}");
+    let res = sandboxed(|| Err(Error::BadTar));
+    assert!(matches!(res, Err(Error::BadTar)), "got {res:?}");
+}